@@ -0,0 +1,14 @@
+uniform float size;
+uniform float light;
+uniform float dark;
+
+out vec4 diffuseColor;
+
+void main()
+{
+    // The checks follow the screen rather than the canvas, so that their size ignores the zoom.
+    float check = mod(floor(gl_FragCoord.x / size) + floor(gl_FragCoord.y / size), 2.0);
+    float grey = mix(light, dark, check);
+
+    diffuseColor = vec4(grey, grey, grey, 1.0);
+}
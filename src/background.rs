@@ -0,0 +1,21 @@
+/// Settings of the checkerboard drawn behind the canvas, showing its transparent pixels:
+/// - The grey levels of the light and dark checks, between 0 and 1.
+/// - The size of a check on screen, in pixels, whatever the zoom.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Checker {
+    pub enabled: bool,
+    pub light: f32,
+    pub dark: f32,
+    pub size: f32,
+}
+
+impl Default for Checker {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            light: 0.8,
+            dark: 0.6,
+            size: 8.0,
+        }
+    }
+}
@@ -0,0 +1,10 @@
+in vec2 pos;
+in vec2 texPos;
+
+uniform mat3 view;
+
+void main()
+{
+    vec3 fpos = vec3(pos, 1) * view;
+    gl_Position = vec4(fpos.xy, 0, 1.0);
+}
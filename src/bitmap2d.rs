@@ -9,6 +9,9 @@ pub trait BitMap2D {
     fn set_bit(&mut self, x:usize, y:usize);
     fn clear_bit(&mut self, x:usize, y:usize);
     fn get_bit(&self, x:usize, y:usize) -> bool;
+
+    /// Iterate over the positions of the set bits, in no particular order.
+    fn iter_set<'a>(&'a self) -> Box<dyn Iterator<Item=(usize, usize)> + 'a>;
 }
 
 impl BitMap2D for HashSet<(usize, usize)> {
@@ -23,6 +26,10 @@ impl BitMap2D for HashSet<(usize, usize)> {
     fn get_bit(&self, x:usize, y:usize) -> bool {
         self.contains(&(x, y))
     }
+
+    fn iter_set<'a>(&'a self) -> Box<dyn Iterator<Item=(usize, usize)> + 'a> {
+        Box::new(self.iter().cloned())
+    }
 }
 
 impl BitMap2D for GrayImage {
@@ -35,6 +42,180 @@ impl BitMap2D for GrayImage {
     }
 
     fn get_bit(&self, x:usize, y:usize) -> bool {
-        self.get_pixel(x as u32, y as u32)[0] == 1u8
+        x < self.width() as usize && y < self.height() as usize
+            && self.get_pixel(x as u32, y as u32)[0] == 1u8
+    }
+
+    fn iter_set<'a>(&'a self) -> Box<dyn Iterator<Item=(usize, usize)> + 'a> {
+        Box::new(self
+            .enumerate_pixels()
+            .filter(|(_, _, pixel)| pixel[0] == 1u8)
+            .map(|(x, y, _)| (x as usize, y as usize)))
+    }
+}
+
+/// A bitmap of a fixed size storing one bit per cell, packed in 64-bit words. Setting, clearing
+/// and reading a bit take constant time, which suits selections covering large canvases.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DenseBitMap2D {
+    size: (usize, usize),
+    words: Vec<u64>,
+}
+
+impl DenseBitMap2D {
+    /// Create a bitmap of the given size with no bit set.
+    pub fn new(w:usize, h:usize) -> Self {
+        Self {
+            size: (w, h),
+            words: vec![0; (w * h + 63) / 64],
+        }
+    }
+
+    /// Create a bitmap of the given size from a set of positions. Positions out of the bitmap are
+    /// dropped.
+    pub fn from_set(set:&HashSet<(usize, usize)>, (w, h):(usize, usize)) -> Self {
+        let mut bitmap = Self::new(w, h);
+        for &(x, y) in set {
+            bitmap.set_bit(x, y);
+        }
+        bitmap
+    }
+
+    /// Return the word holding a bit and the mask of the bit in this word, if it is in the bitmap.
+    fn locate(&self, x:usize, y:usize) -> Option<(usize, u64)> {
+        let (w, h) = self.size;
+        if x >= w || y >= h {
+            return None
+        }
+
+        let id = y * w + x;
+        Some((id / 64, 1 << (id % 64)))
+    }
+}
+
+impl BitMap2D for DenseBitMap2D {
+    fn set_bit(&mut self, x:usize, y:usize) {
+        if let Some((word, mask)) = self.locate(x, y) {
+            self.words[word] |= mask;
+        }
+    }
+
+    fn clear_bit(&mut self, x:usize, y:usize) {
+        if let Some((word, mask)) = self.locate(x, y) {
+            self.words[word] &= !mask;
+        }
+    }
+
+    fn get_bit(&self, x:usize, y:usize) -> bool {
+        match self.locate(x, y) {
+            Some((word, mask)) => self.words[word] & mask != 0,
+            None => false,
+        }
+    }
+
+    fn iter_set<'a>(&'a self) -> Box<dyn Iterator<Item=(usize, usize)> + 'a> {
+        let w = self.size.0;
+        Box::new(self.words
+            .iter()
+            .enumerate()
+            .filter(|(_, &word)| word != 0)
+            .flat_map(|(i, &word)| {
+                (0..64).filter(move |bit| word & (1 << bit) != 0).map(move |bit| i * 64 + bit)
+            })
+            .map(move |id| (id % w, id / w)))
+    }
+}
+
+impl From<&DenseBitMap2D> for HashSet<(usize, usize)> {
+    fn from(bitmap:&DenseBitMap2D) -> Self {
+        bitmap.iter_set().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    /// Positions spread over a bitmap whose width is no multiple of 64, so that rows straddle
+    /// words.
+    fn scattered((w, h):(usize, usize)) -> HashSet<(usize, usize)> {
+        (0..h)
+            .flat_map(|y| (0..w).map(move |x| (x, y)))
+            .filter(|&(x, y)| (x * 7 + y * 3) % 5 == 0)
+            .collect()
+    }
+
+    #[test]
+    fn set_get_and_clear_bits() {
+        let mut bitmap = DenseBitMap2D::new(13, 7);
+        bitmap.set_bit(12, 6);
+        bitmap.set_bit(0, 5);
+        bitmap.set_bit(13, 0);
+        bitmap.set_bit(0, 7);
+        assert!(bitmap.get_bit(12, 6));
+        assert!(bitmap.get_bit(0, 5));
+        assert!(!bitmap.get_bit(13, 0));
+        assert!(!bitmap.get_bit(0, 7));
+        assert!(!bitmap.get_bit(usize::MAX, 0));
+        assert_eq!(bitmap.iter_set().count(), 2);
+
+        bitmap.clear_bit(12, 6);
+        assert!(!bitmap.get_bit(12, 6));
+        assert_eq!(bitmap.iter_set().count(), 1);
+    }
+
+    #[test]
+    fn iterate_set_bits() {
+        let set = scattered((13, 7));
+        let bitmap = DenseBitMap2D::from_set(&set, (13, 7));
+        let mut bits : Vec<_> = bitmap.iter_set().collect();
+        let mut expected : Vec<_> = set.iter().cloned().collect();
+        bits.sort();
+        expected.sort();
+        assert_eq!(bits, expected);
+        assert_eq!(bitmap.iter_set().count(), set.len());
+        assert_eq!(HashSet::from(&bitmap), set);
+    }
+
+    #[test]
+    fn drop_positions_out_of_bitmap() {
+        let set : HashSet<_> = [(1, 1), (4, 1), (1, 3)].iter().cloned().collect();
+        let bitmap = DenseBitMap2D::from_set(&set, (4, 3));
+        assert_eq!(bitmap.iter_set().collect::<Vec<_>>(), vec![(1, 1)]);
+    }
+
+    /// Time the selection of a whole 512x512 canvas and the lookups of the neighbours of every
+    /// pixel done to build its vertices, with both representations. Run it with
+    /// `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_full_selection() {
+        const AROUND : [(isize, isize); 8] =
+            [(0, -1), (1, -1), (1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1)];
+
+        fn bench<B:BitMap2D>(name:&str, mut bitmap:B) {
+            let start = Instant::now();
+            for y in 0..512 {
+                for x in 0..512 {
+                    bitmap.set_bit(x, y);
+                }
+            }
+            let selected = start.elapsed();
+
+            let start = Instant::now();
+            let mut neighbours = 0;
+            for (x, y) in bitmap.iter_set() {
+                for &(dx, dy) in &AROUND {
+                    let (nx, ny) = ((x as isize + dx) as usize, (y as isize + dy) as usize);
+                    neighbours += bitmap.get_bit(nx, ny) as usize;
+                }
+            }
+            println!("{}: selected in {:?}, neighbours looked up in {:?} ({})",
+                name, selected, start.elapsed(), neighbours);
+        }
+
+        bench("HashSet", HashSet::new());
+        bench("DenseBitMap2D", DenseBitMap2D::new(512, 512));
     }
 }
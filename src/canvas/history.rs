@@ -0,0 +1,129 @@
+use std::collections::VecDeque;
+use super::{Layer, Pixel};
+
+/// Default number of transactions kept by a `History`.
+pub const DEFAULT_HISTORY_DEPTH : usize = 256;
+
+/// A single pixel modification: the layer and position where it happened, the color it had and
+/// the color it was given.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PixelDiff {
+    pub layer: usize,
+    pub pos: (usize, usize),
+    pub old: Pixel,
+    pub new: Pixel,
+}
+
+/// The size and layers of a canvas.
+pub type Buffer = ((usize, usize), Vec<Layer>);
+
+/// The colors the pixels are restricted to in indexed mode, none outside of it.
+pub type IndexedPalette = Option<Vec<(u8, u8, u8)>>;
+
+/// A modification of a canvas: either a single pixel, all of its layers, for the operations
+/// changing its size or its layers, or the palette of its indexed mode.
+#[derive(Clone, Debug)]
+pub enum Change {
+    Pixel(PixelDiff),
+    Buffer { old: Buffer, new: Buffer },
+    Palette { old: IndexedPalette, new: IndexedPalette },
+}
+
+/// A group of modifications undone and redone as a whole.
+pub type Transaction = Vec<Change>;
+
+/// This structure records the modifications done on a canvas so that they can be undone:
+/// - The committed transactions, oldest first, capped to `depth` entries.
+/// - The undone transactions, waiting to be redone.
+/// - The transaction currently being recorded, if any.
+pub struct History {
+    undo: VecDeque<Transaction>,
+    redo: Vec<Transaction>,
+    current: Option<Transaction>,
+    depth: usize,
+}
+
+impl History {
+    pub fn new(depth:usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            current: None,
+            depth,
+        }
+    }
+
+    /// Open a new transaction. Does nothing if one is already open.
+    pub fn begin(&mut self) {
+        if self.current.is_none() {
+            self.current = Some(Transaction::new());
+        }
+    }
+
+    /// Add a modification to the open transaction. Modifications done outside of a transaction are
+    /// not recorded.
+    pub fn record(&mut self, layer:usize, pos:(usize, usize), old:Pixel, new:Pixel) {
+        if let Some(transaction) = self.current.as_mut() {
+            transaction.push(Change::Pixel(PixelDiff { layer, pos, old, new }));
+        }
+    }
+
+    /// Add the replacement of all the layers to the open transaction.
+    pub fn record_buffer(&mut self, old:Buffer, new:Buffer) {
+        if let Some(transaction) = self.current.as_mut() {
+            transaction.push(Change::Buffer { old, new });
+        }
+    }
+
+    /// Add the change of the palette of the indexed mode to the open transaction.
+    pub fn record_palette(&mut self, old:IndexedPalette, new:IndexedPalette) {
+        if let Some(transaction) = self.current.as_mut() {
+            transaction.push(Change::Palette { old, new });
+        }
+    }
+
+    /// Close the open transaction and push it on the undo stack. Empty transactions are dropped,
+    /// other ones invalidate the redo stack.
+    pub fn commit(&mut self) {
+        if let Some(transaction) = self.current.take() {
+            if !transaction.is_empty() {
+                self.redo.clear();
+                self.undo.push_back(transaction);
+                self.truncate();
+            }
+        }
+    }
+
+    /// Pop the last committed transaction, which must then be reverted by the caller.
+    pub fn undo(&mut self) -> Option<&Transaction> {
+        self.commit();
+        let transaction = self.undo.pop_back()?;
+        self.redo.push(transaction);
+        self.redo.last()
+    }
+
+    /// Pop the last undone transaction, which must then be reapplied by the caller.
+    pub fn redo(&mut self) -> Option<&Transaction> {
+        self.commit();
+        let transaction = self.redo.pop()?;
+        self.undo.push_back(transaction);
+        self.undo.back()
+    }
+
+    pub fn set_depth(&mut self, depth:usize) {
+        self.depth = depth;
+        self.truncate();
+    }
+
+    fn truncate(&mut self) {
+        while self.undo.len() > self.depth {
+            self.undo.pop_front();
+        }
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(DEFAULT_HISTORY_DEPTH)
+    }
+}
@@ -0,0 +1,55 @@
+use super::Pixel;
+
+/// This structure represent a layer of a canvas:
+/// - Its name, shown to the user.
+/// - Whether it is drawn, and with which opacity over the layers below it.
+/// - Its data (a big array of Width x Height pixels).
+#[derive(Clone, Debug)]
+pub struct Layer {
+    pub name : String,
+    pub visible : bool,
+    pub opacity : f32,
+    pub(super) data : Vec<Pixel>,
+}
+
+impl Layer {
+    /// Create a fully transparent layer.
+    pub fn new<S:Into<String>>(name:S, w:usize, h:usize) -> Self {
+        Self::filled(name, w, h, (0, 0, 0, 0))
+    }
+
+    /// Create a layer whose pixels all have the same color.
+    pub fn filled<S:Into<String>>(name:S, w:usize, h:usize, pixel:Pixel) -> Self {
+        Self {
+            name: name.into(),
+            visible: true,
+            opacity: 1.0,
+            data: vec![pixel; w * h],
+        }
+    }
+
+    /// Create a visible and opaque layer from its pixels, row by row.
+    pub fn from_pixels<S:Into<String>>(name:S, data:Vec<Pixel>) -> Self {
+        Self {
+            name: name.into(),
+            visible: true,
+            opacity: 1.0,
+            data,
+        }
+    }
+
+    /// Return the pixels of the layer, row by row.
+    pub fn pixels(&self) -> &[Pixel] {
+        &self.data
+    }
+
+    /// Create a layer with the same properties as this one, but other pixels.
+    pub(super) fn with_data(&self, data:Vec<Pixel>) -> Self {
+        Self {
+            name: self.name.clone(),
+            visible: self.visible,
+            opacity: self.opacity,
+            data,
+        }
+    }
+}
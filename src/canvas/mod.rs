@@ -1,56 +1,989 @@
+mod history;
+mod layer;
 mod shader;
 
+use std::{cell::Cell, collections::HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use image::{RgbaImage, Rgba};
+use luminance::{
+    texture::{Texture, Dim2, GenMipmaps, TextureError},
+    pixel::NormRGBA8UI,
+};
+
+pub use history::*;
+pub use layer::*;
 pub use shader::*;
 
+use crate::color;
+use crate::rng::Rng;
+
+/// A pixel: its red, green, blue and alpha channels.
+pub type Pixel = (u8, u8, u8, u8);
+
 /// This structure represent a VIPix canvas:
 /// - Its size in pixels (Width, Height).
-/// - Its data (a big array of Width x Height pixels).
+/// - Its layers, from the bottom one to the top one, and the one being drawn on.
+/// - The composition of its visible layers, which is what is displayed.
+/// - The history of the modifications done on its layers.
+/// - The rectangle of the composition modified since it was last uploaded to a texture, as its
+///   top-left and bottom-right corners.
+/// - In indexed mode, the palette its pixels are restricted to. The pixels still hold RGBA values,
+///   each one being one of the colors of the palette, so that an entry is known by its color.
+/// - Its generation, a number no other state of any canvas had, given anew whenever its
+///   composition changes, and its generation when it was last written or created.
 pub struct Canvas {
     size : (usize, usize),
-    data : Vec<(u8, u8, u8)>,
+    layers : Vec<Layer>,
+    active : usize,
+    composite : Vec<Pixel>,
+    history : History,
+    dirty : Cell<Option<((usize, usize), (usize, usize))>>,
+    indexed : IndexedPalette,
+    generation : Cell<u64>,
+    written : u64,
+}
+
+/// Source of the generations of the canvases.
+static GENERATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Position of a pixel of a canvas of the given size once the canvas is rotated clockwise.
+pub fn rotated_cw((_, h):(usize, usize), (x, y):(usize, usize)) -> (usize, usize) {
+    (h - 1 - y, x)
+}
+
+/// Position of a pixel of a canvas of the given size once the canvas is rotated counterclockwise.
+pub fn rotated_ccw((w, _):(usize, usize), (x, y):(usize, usize)) -> (usize, usize) {
+    (y, w - 1 - x)
+}
+
+/// Straight alpha blending of a pixel over another one, the alpha of the pixel on top being
+/// multiplied by the given opacity.
+pub fn over((dr, dg, db, da):Pixel, (sr, sg, sb, sa):Pixel, opacity:f32) -> Pixel {
+    let sa = sa as f32 / 255.0 * opacity;
+    let da = da as f32 / 255.0;
+    let a = sa + da * (1.0 - sa);
+    if a <= 0.0 {
+        return (0, 0, 0, 0)
+    }
+
+    let mix = |d:u8, s:u8| ((s as f32 * sa + d as f32 * da * (1.0 - sa)) / a).round() as u8;
+    (mix(dr, sr), mix(dg, sg), mix(db, sb), (a * 255.0).round() as u8)
 }
 
 impl Canvas {
     pub fn new(x:usize, y:usize) -> Self {
-        Self {
-            size: (x, y),
-            data: vec![(0, 0, 0); x * y],
+        Self::from_layers((x, y), vec![Layer::filled("Layer 1", x, y, (0, 0, 0, 255))])
+    }
+
+    /// Create a canvas holding the pixels of an image.
+    pub fn from_rgba_image(img:&RgbaImage) -> Self {
+        let (w, h) = img.dimensions();
+        let (w, h) = (w as usize, h as usize);
+        let mut layer = Layer::new("Layer 1", w, h);
+        layer.data = img.pixels().map(|&Rgba([r, g, b, a])| (r, g, b, a)).collect();
+        Self::from_layers((w, h), vec![layer])
+    }
+
+    /// Create a canvas of the given size from its layers, from the bottom one to the top one,
+    /// each one holding a pixel for every position of the canvas.
+    pub fn from_layers(size:(usize, usize), layers:Vec<Layer>) -> Self {
+        let mut canvas = Self {
+            size,
+            layers,
+            active: 0,
+            composite: Vec::new(),
+            history: History::default(),
+            dirty: Cell::new(None),
+            indexed: None,
+            generation: Cell::new(0),
+            written: 0,
+        };
+
+        canvas.update_composite();
+        canvas.written = canvas.generation();
+        canvas
+    }
+
+    /// Set a pixel of the active layer. In indexed mode, the pixel is given the nearest color of
+    /// the palette.
+    pub fn set_pixel(&mut self, x:usize, y:usize, mut new:Pixel) {
+        let (w, h) = self.size;
+        let id = y * w + x;
+
+        assert!(id < w*h);
+
+        if let Some(palette) = self.indexed.as_ref().filter(|palette| !palette.is_empty()) {
+            let (r, g, b, a) = new;
+            if a != 0 {
+                let (r, g, b) = palette[color::nearest((r, g, b), palette)];
+                new = (r, g, b, a);
+            }
         }
+
+        let old = self.layers[self.active].data[id];
+        if old != new {
+            self.record(x, y, old, new);
+            self.layers[self.active].data[id] = new;
+            self.composite[id] = self.composite_pixel(id);
+            self.mark_dirty(x, y);
+        }
+    }
+
+    /// Set the color of a pixel of the active layer. Without alpha, the pixel is opaque.
+    pub fn set_pixel_color(&mut self, x:usize, y:usize, (r, g, b):(u8, u8, u8), alpha:Option<u8>) {
+        self.set_pixel(x, y, (r, g, b, alpha.unwrap_or(255)))
+    }
+
+    /// Set an opaque pixel of the active layer.
+    pub fn set_pixel_color_rgb(&mut self, x:usize, y:usize, rgb:(u8, u8, u8)) {
+        self.set_pixel_color(x, y, rgb, None)
+    }
+
+    /// Paint a color over a pixel of the active layer with the given opacity, between 0 and 1.
+    /// Painting the same pixel again builds up toward the color.
+    pub fn blend_pixel(&mut self, x:usize, y:usize, (r, g, b):(u8, u8, u8), alpha:f32) {
+        let old = self.get_pixel(x, y);
+        self.set_pixel(x, y, over(old, (r, g, b, 255), alpha.max(0.0).min(1.0)))
+    }
+
+    /// Make a pixel of the active layer fully transparent.
+    pub fn clear_pixel(&mut self, x:usize, y:usize) {
+        self.set_pixel(x, y, (0, 0, 0, 0))
     }
 
-    pub fn set_pixel_color(&mut self, x:usize, y:usize, rgb:(u8, u8, u8)) {
+    /// Pixel of the active layer, with its alpha.
+    pub fn get_pixel(&self, x:usize, y:usize) -> Pixel {
         let (w, h) = self.size;
         let id = y * w + x;
 
         assert!(id < w*h);
 
-        self.data[y * w + x] = rgb;
+        self.layers[self.active].data[id]
     }
 
+    /// Color of a pixel of the active layer.
     pub fn get_pixel_color(&self, x:usize, y:usize) -> (u8, u8, u8) {
+        let (r, g, b, _) = self.get_pixel(x, y);
+        (r, g, b)
+    }
+
+    /// Color of a pixel as displayed, that is once all the visible layers are composed.
+    pub fn get_composite_color(&self, x:usize, y:usize) -> (u8, u8, u8) {
         let (w, h) = self.size;
         let id = y * w + x;
 
         assert!(id < w*h);
 
-        self.data[y * w + x]
+        let (r, g, b, _) = self.composite[id];
+        (r, g, b)
     }
 
     pub fn size(&self) -> (usize, usize) {
         self.size
     }
+
+    fn composite_pixel(&self, id:usize) -> Pixel {
+        self.layers
+            .iter()
+            .filter(|layer| layer.visible)
+            .fold((0, 0, 0, 0), |pixel, layer| over(pixel, layer.data[id], layer.opacity))
+    }
+
+    fn update_composite(&mut self) {
+        let (w, h) = self.size;
+        self.composite = (0..w * h).map(|id| self.composite_pixel(id)).collect();
+        self.dirty.set(Some(((0, 0), (w.saturating_sub(1), h.saturating_sub(1)))));
+        self.generation.set(GENERATIONS.fetch_add(1, Ordering::Relaxed));
+    }
+
+    /// Add a pixel to the rectangle to upload.
+    fn mark_dirty(&self, x:usize, y:usize) {
+        let rect = match self.dirty.get() {
+            Some(((x1, y1), (x2, y2))) => ((x1.min(x), y1.min(y)), (x2.max(x), y2.max(y))),
+            None => ((x, y), (x, y)),
+        };
+        self.dirty.set(Some(rect));
+        self.generation.set(GENERATIONS.fetch_add(1, Ordering::Relaxed));
+    }
+
+    /// Return the generation of the canvas: while it stays the same, so does the composition.
+    pub fn generation(&self) -> u64 {
+        self.generation.get()
+    }
+
+    /// Remember that the canvas, as it is now, has been written.
+    pub fn set_written(&mut self) {
+        self.written = self.generation();
+    }
+
+    /// Whether the composition changed since the canvas was last written or created.
+    pub fn is_modified(&self) -> bool {
+        self.generation() != self.written
+    }
+
+    /// Upload the pixels modified since the last upload to a texture of the size of the canvas.
+    /// Nothing is uploaded if no pixel was modified.
+    pub fn upload_dirty(&self, tex:&Texture<Dim2, NormRGBA8UI>) -> Result<(), TextureError> {
+        let ((x1, y1), (x2, y2)) = match self.dirty.take() {
+            Some(rect) => rect,
+            None => return Ok(()),
+        };
+
+        let (w, h) = self.size;
+        if w == 0 || h == 0 {
+            return Ok(())
+        }
+
+        let texels : Vec<Pixel> = (y1..y2 + 1)
+            .flat_map(|y| self.composite[y * w + x1..y * w + x2 + 1].iter().cloned())
+            .collect();
+
+        let size = [(x2 - x1 + 1) as u32, (y2 - y1 + 1) as u32];
+        tex.upload_part(GenMipmaps::No, [x1 as u32, y1 as u32], size, &texels)
+    }
+
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    pub fn active_layer(&self) -> usize {
+        self.active
+    }
+
+    /// Select the layer the following modifications are done on.
+    pub fn set_active_layer(&mut self, layer:usize) {
+        self.active = layer.min(self.layers.len() - 1);
+    }
+
+    /// Add an empty layer just above the active one, and make it active.
+    pub fn add_layer(&mut self) {
+        let (w, h) = self.size;
+        let mut layers = self.layers.clone();
+        let name = format!("Layer {}", layers.len() + 1);
+        layers.insert(self.active + 1, Layer::new(name, w, h));
+
+        self.replace_layers(self.size, layers);
+        self.active += 1;
+    }
+
+    /// Show or hide the active layer.
+    pub fn toggle_layer_visibility(&mut self) {
+        let mut layers = self.layers.clone();
+        layers[self.active].visible = !layers[self.active].visible;
+        self.replace_layers(self.size, layers);
+    }
+
+    /// Set the opacity of the active layer, between 0 and 1.
+    pub fn set_layer_opacity(&mut self, opacity:f32) {
+        let mut layers = self.layers.clone();
+        layers[self.active].opacity = opacity.max(0.0).min(1.0);
+        self.replace_layers(self.size, layers);
+    }
+
+    /// Return the 4-connected region of pixels sharing the color of (x, y).
+    pub fn contiguous_region(&self, x:usize, y:usize) -> Vec<(usize, usize)> {
+        self.contiguous_region_in(x, y, (0, self.size.0))
+    }
+
+    /// Return the 4-connected region of pixels sharing the color of (x, y) within the columns
+    /// given by the first one and their number, which is empty if (x, y) is out of them.
+    pub fn contiguous_region_in(&self, x:usize, y:usize, (x0, width):(usize, usize))
+        -> Vec<(usize, usize)> {
+        let (w, h) = self.size;
+        let x1 = (x0 + width).min(w);
+        if x < x0 || x >= x1 {
+            return Vec::new()
+        }
+
+        let target = self.get_pixel(x, y);
+        let data = &self.layers[self.active].data;
+
+        let mut visited = vec![false; w * h];
+        let mut region = Vec::new();
+        let mut stack = vec![(x, y)];
+
+        while let Some((x, y)) = stack.pop() {
+            if visited[y * w + x] || data[y * w + x] != target {
+                continue
+            }
+
+            visited[y * w + x] = true;
+            region.push((x, y));
+
+            if x > x0 { stack.push((x - 1, y)) }
+            if y > 0 { stack.push((x, y - 1)) }
+            if x + 1 < x1 { stack.push((x + 1, y)) }
+            if y + 1 < h { stack.push((x, y + 1)) }
+        }
+
+        region
+    }
+
+    /// Return every pixel of the active layer sharing the color of (x, y), connected or not.
+    pub fn same_color_pixels(&self, x:usize, y:usize) -> Vec<(usize, usize)> {
+        let target = self.get_pixel(x, y);
+        self.all_pixels()
+            .into_iter()
+            .filter(|&(x, y)| self.get_pixel(x, y) == target)
+            .collect()
+    }
+
+    /// Paint the 4-connected region of pixels sharing the color of (x, y) within the given
+    /// columns, as for `contiguous_region_in`, and return the pixels changed.
+    pub fn flood_fill(&mut self, x:usize, y:usize, new:(u8, u8, u8), columns:(usize, usize))
+        -> Vec<(usize, usize)> {
+        let (r, g, b) = new;
+        if self.get_pixel(x, y) == (r, g, b, 255) {
+            return Vec::new()
+        }
+
+        let region = self.contiguous_region_in(x, y, columns);
+        for &(x, y) in &region {
+            self.set_pixel_color_rgb(x, y, new);
+        }
+
+        region
+    }
+
+    /// Give every pixel of the active layer having exactly a color another one, keeping its alpha,
+    /// and return the pixels changed.
+    pub fn replace_color(&mut self, from:(u8, u8, u8), to:(u8, u8, u8)) -> Vec<(usize, usize)> {
+        self.replace_color_within(from, to, 0)
+    }
+
+    /// Give every pixel of the active layer whose channels are each at most `tolerance` away from
+    /// a color another one, keeping its alpha, and return the pixels changed.
+    pub fn replace_color_within(&mut self, from:(u8, u8, u8), (r, g, b):(u8, u8, u8),
+                                tolerance:u8) -> Vec<(usize, usize)> {
+        let near = |a:u8, b:u8| (a as i16 - b as i16).abs() <= tolerance as i16;
+        let matching : Vec<(usize, usize)> = self.all_pixels()
+            .into_iter()
+            .filter(|&(x, y)| {
+                let (pr, pg, pb, _) = self.get_pixel(x, y);
+                near(pr, from.0) && near(pg, from.1) && near(pb, from.2)
+            })
+            .collect();
+
+        for &(x, y) in &matching {
+            let (_, _, _, a) = self.get_pixel(x, y);
+            self.set_pixel(x, y, (r, g, b, a));
+        }
+
+        matching
+    }
+
+    /// Apply a function to the pixels of the active layer in the given region, or to all of them,
+    /// and return the pixels changed.
+    fn map_pixels<F>(&mut self, region:Option<&HashSet<(usize, usize)>>, f:F) -> Vec<(usize, usize)>
+        where F : Fn(Pixel) -> Pixel
+    {
+        let positions = match region {
+            Some(region) => region.iter().cloned().collect(),
+            None => self.all_pixels(),
+        };
+
+        let mut changed = Vec::new();
+        for (x, y) in positions {
+            let old = self.get_pixel(x, y);
+            let new = f(old);
+            if old != new {
+                self.set_pixel(x, y, new);
+                changed.push((x, y));
+            }
+        }
+
+        changed
+    }
+
+    /// Replace each channel of the pixels of the active layer in the given region, or of all of
+    /// them, by 255 minus its value, and return the pixels changed. Alpha is kept.
+    pub fn invert(&mut self, region:Option<&HashSet<(usize, usize)>>) -> Vec<(usize, usize)> {
+        self.map_pixels(region, |(r, g, b, a)| (255 - r, 255 - g, 255 - b, a))
+    }
+
+    /// Give a color to the pixels of the active layer in the given region, or to all of them, and
+    /// return the pixels changed. The pixels become opaque.
+    pub fn fill(&mut self, (r, g, b):(u8, u8, u8), region:Option<&HashSet<(usize, usize)>>)
+        -> Vec<(usize, usize)> {
+        self.map_pixels(region, |_| (r, g, b, 255))
+    }
+
+    /// Add a random value in `[-amount, amount]` to each channel of the pixels of the active layer
+    /// in the given region, or of all of them, and return the pixels changed. Alpha is kept.
+    pub fn noise(&mut self, region:Option<&HashSet<(usize, usize)>>, amount:u8, rng:&mut Rng)
+        -> Vec<(usize, usize)> {
+        let amount = amount as i32;
+        let mut positions = match region {
+            Some(region) => region.iter().cloned().collect(),
+            None => self.all_pixels(),
+        };
+        // the same seed gives the same noise whatever the order of the region
+        positions.sort();
+
+        let mut changed = Vec::new();
+        for (x, y) in positions {
+            let (r, g, b, a) = self.get_pixel(x, y);
+            let mut shift = |c:u8| (c as i32 + rng.range(-amount, amount)).max(0).min(255) as u8;
+            let new = (shift(r), shift(g), shift(b), a);
+            if new != (r, g, b, a) {
+                self.set_pixel(x, y, new);
+                changed.push((x, y));
+            }
+        }
+
+        changed
+    }
+
+    /// Replace the pixels of the active layer in the given region, or all of them, by one color or
+    /// the other following a 4x4 ordered (Bayer) dither of their luma: the darkest pixels take
+    /// the first color, the lightest the second, and the ones in between a pattern of both.
+    /// Return the pixels changed. Alpha is kept.
+    pub fn dither(&mut self, region:Option<&HashSet<(usize, usize)>>, dark:(u8, u8, u8),
+                  light:(u8, u8, u8)) -> Vec<(usize, usize)> {
+        const BAYER : [[u8; 4]; 4] = [
+            [ 0,  8,  2, 10],
+            [12,  4, 14,  6],
+            [ 3, 11,  1,  9],
+            [15,  7, 13,  5],
+        ];
+
+        let positions = match region {
+            Some(region) => region.iter().cloned().collect(),
+            None => self.all_pixels(),
+        };
+
+        let mut changed = Vec::new();
+        for (x, y) in positions {
+            let old = self.get_pixel(x, y);
+            let (r, g, b, a) = old;
+            let luma = (0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32) / 255.0;
+            let threshold = (BAYER[y % 4][x % 4] as f32 + 0.5) / 16.0;
+            let (r, g, b) = if luma > threshold { light } else { dark };
+            if old != (r, g, b, a) {
+                self.set_pixel(x, y, (r, g, b, a));
+                changed.push((x, y));
+            }
+        }
+
+        changed
+    }
+
+    /// Replace the pixels of the active layer in the given region, or all of them, by their luma,
+    /// and return the pixels changed. Alpha is kept.
+    pub fn grayscale(&mut self, region:Option<&HashSet<(usize, usize)>>) -> Vec<(usize, usize)> {
+        self.map_pixels(region, |(r, g, b, a)| {
+            let luma = (0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32).round() as u8;
+            (luma, luma, luma, a)
+        })
+    }
+
+    /// Replace the color of each pixel of the active layer by the nearest color of the palette, and
+    /// return the pixels changed. Alpha is kept, and transparent pixels are left untouched.
+    pub fn quantize(&mut self, palette:&[(u8, u8, u8)]) -> Vec<(usize, usize)> {
+        if palette.is_empty() {
+            return Vec::new()
+        }
+
+        self.map_pixels(None, |(r, g, b, a)| {
+            if a == 0 {
+                return (r, g, b, a)
+            }
+
+            let (r, g, b) = palette[color::nearest((r, g, b), palette)];
+            (r, g, b, a)
+        })
+    }
+
+    /// Replace the color of each pixel of the active layer by the nearest color of the palette,
+    /// spreading the difference between both colors over the neighbouring pixels not replaced
+    /// yet (Floyd-Steinberg dithering), and return the pixels changed. Alpha is kept, and
+    /// transparent pixels are left untouched.
+    pub fn quantize_dithered(&mut self, palette:&[(u8, u8, u8)]) -> Vec<(usize, usize)> {
+        if palette.is_empty() {
+            return Vec::new()
+        }
+
+        let (w, h) = self.size;
+        let mut errors = vec![(0.0f32, 0.0f32, 0.0f32); w * h];
+        let mut changed = Vec::new();
+
+        for y in 0..h {
+            for x in 0..w {
+                let old = self.get_pixel(x, y);
+                let (r, g, b, a) = old;
+                if a == 0 {
+                    continue
+                }
+
+                let (er, eg, eb) = errors[y * w + x];
+                let wanted = (r as f32 + er, g as f32 + eg, b as f32 + eb);
+                let clamp = |c:f32| c.round().max(0.0).min(255.0) as u8;
+                let wanted_color = (clamp(wanted.0), clamp(wanted.1), clamp(wanted.2));
+                let (nr, ng, nb) = palette[color::nearest(wanted_color, palette)];
+                let error = (wanted.0 - nr as f32, wanted.1 - ng as f32, wanted.2 - nb as f32);
+
+                for &(dx, dy, weight) in &[(1, 0, 7.0), (-1, 1, 3.0), (0, 1, 5.0), (1, 1, 1.0)] {
+                    let (nx, ny) = (x as isize + dx, y + dy);
+                    if nx < 0 || nx as usize >= w || ny >= h {
+                        continue
+                    }
+
+                    let neighbour = &mut errors[ny * w + nx as usize];
+                    neighbour.0 += error.0 * weight / 16.0;
+                    neighbour.1 += error.1 * weight / 16.0;
+                    neighbour.2 += error.2 * weight / 16.0;
+                }
+
+                let new = (nr, ng, nb, a);
+                if old != new {
+                    self.set_pixel(x, y, new);
+                    changed.push((x, y));
+                }
+            }
+        }
+
+        changed
+    }
+
+    /// Restrict the pixels of every layer to the colors of a palette, quantizing them, or lift the
+    /// restriction, the pixels keeping their colors. Return the pixels changed.
+    pub fn set_indexed(&mut self, palette:IndexedPalette) -> Vec<(usize, usize)> {
+        self.set_palette(palette.clone());
+        let palette = match palette {
+            Some(palette) => palette,
+            None => return Vec::new(),
+        };
+
+        let active = self.active;
+        let mut changed = Vec::new();
+        for layer in 0..self.layers.len() {
+            self.active = layer;
+            changed.extend(self.quantize(&palette));
+        }
+        self.active = active;
+
+        changed
+    }
+
+    pub fn is_indexed(&self) -> bool {
+        self.indexed.is_some()
+    }
+
+    /// Return the colors the pixels are restricted to in indexed mode.
+    pub fn indexed_palette(&self) -> Option<&[(u8, u8, u8)]> {
+        self.indexed.as_deref()
+    }
+
+    /// Replace the palette of the indexed mode, recording the change in the current transaction.
+    fn set_palette(&mut self, palette:IndexedPalette) {
+        let old = std::mem::replace(&mut self.indexed, palette);
+        self.history.record_palette(old, self.indexed.clone());
+    }
+
+    /// In indexed mode, change the color of an entry of the palette, giving the new color to the
+    /// pixels of every layer which had the old one, and return the pixels changed. As the pixels
+    /// hold colors rather than indices, they are left as they are while another entry still has
+    /// the old color, not knowing which of the two they were painted with.
+    pub fn recolor(&mut self, index:usize, new:(u8, u8, u8)) -> Vec<(usize, usize)> {
+        let mut palette = match self.indexed.clone() {
+            Some(palette) if index < palette.len() => palette,
+            _ => return Vec::new(),
+        };
+
+        let old = std::mem::replace(&mut palette[index], new);
+        let shared = palette.contains(&old);
+        self.set_palette(Some(palette));
+        if shared {
+            return Vec::new()
+        }
+
+        let active = self.active;
+        let mut changed = Vec::new();
+        for layer in 0..self.layers.len() {
+            self.active = layer;
+            changed.extend(self.replace_color(old, new));
+        }
+        self.active = active;
+
+        changed
+    }
+
+    /// Shift the hue of the pixels of the active layer in the given region, or of all of them, by
+    /// `dh` degrees, and their saturation and value by `ds` and `dv`, and return the pixels
+    /// changed. The hue wraps around, the saturation and the value are kept within `[0, 1]`.
+    /// Alpha is kept.
+    pub fn adjust_hsv(&mut self, region:Option<&HashSet<(usize, usize)>>,
+                      (dh, ds, dv):(f32, f32, f32)) -> Vec<(usize, usize)> {
+        self.map_pixels(region, |(r, g, b, a)| {
+            let (h, s, v) = color::to_hsv((r, g, b));
+            let clamp = |c:f32| c.max(0.0).min(1.0);
+            let (r, g, b) = color::from_hsv((h + dh, clamp(s + ds), clamp(v + dv)));
+            (r, g, b, a)
+        })
+    }
+
+    /// Add `delta` to each channel of the pixels of the active layer in the given region, or of
+    /// all of them, and return the pixels changed. Alpha is kept.
+    pub fn brightness(&mut self, region:Option<&HashSet<(usize, usize)>>, delta:i16)
+        -> Vec<(usize, usize)> {
+        let shift = |c:u8| (c as i32 + delta as i32).max(0).min(255) as u8;
+        self.map_pixels(region, |(r, g, b, a)| (shift(r), shift(g), shift(b), a))
+    }
+
+    /// Scale the distance of each channel of the pixels of the active layer in the given region,
+    /// or of all of them, to 128 by `factor`, and return the pixels changed. Alpha is kept.
+    pub fn contrast(&mut self, region:Option<&HashSet<(usize, usize)>>, factor:f32)
+        -> Vec<(usize, usize)> {
+        let scale = |c:u8| ((c as f32 - 128.0) * factor + 128.0).round().max(0.0).min(255.0) as u8;
+        self.map_pixels(region, |(r, g, b, a)| (scale(r), scale(g), scale(b), a))
+    }
+
+    /// Convert the displayed canvas into an image, to be written into a file.
+    pub fn to_rgba_image(&self) -> RgbaImage {
+        let (w, h) = self.size;
+        RgbaImage::from_fn(w as u32, h as u32, |x, y| {
+            let (r, g, b, a) = self.composite[y as usize * w + x as usize];
+            Rgba([r, g, b, a])
+        })
+    }
+
+    /// Start grouping the following modifications in a single undoable transaction.
+    pub fn begin_transaction(&mut self) {
+        self.history.begin()
+    }
+
+    /// Close the current transaction, making it available to `undo`.
+    pub fn commit_transaction(&mut self) {
+        self.history.commit()
+    }
+
+    /// Explicitly record a modification of the active layer in the current transaction.
+    pub fn record(&mut self, x:usize, y:usize, old:Pixel, new:Pixel) {
+        self.history.record(self.active, (x, y), old, new)
+    }
+
+    /// Revert the last transaction and return the pixels it modified.
+    pub fn undo(&mut self) -> Vec<(usize, usize)> {
+        let transaction = match self.history.undo() {
+            Some(transaction) => transaction.clone(),
+            None => return Vec::new(),
+        };
+
+        transaction
+            .into_iter()
+            .rev()
+            .flat_map(|change| match change {
+                Change::Pixel(PixelDiff { layer, pos, old, .. }) => self.apply_pixel(layer, pos, old),
+                Change::Buffer { old, .. } => self.apply_buffer(old),
+                Change::Palette { old, .. } => self.apply_palette(old),
+            })
+            .collect()
+    }
+
+    /// Reapply the last undone transaction and return the pixels it modified.
+    pub fn redo(&mut self) -> Vec<(usize, usize)> {
+        let transaction = match self.history.redo() {
+            Some(transaction) => transaction.clone(),
+            None => return Vec::new(),
+        };
+
+        transaction
+            .into_iter()
+            .flat_map(|change| match change {
+                Change::Pixel(PixelDiff { layer, pos, new, .. }) => self.apply_pixel(layer, pos, new),
+                Change::Buffer { new, .. } => self.apply_buffer(new),
+                Change::Palette { new, .. } => self.apply_palette(new),
+            })
+            .collect()
+    }
+
+    fn apply_pixel(&mut self, layer:usize, (x, y):(usize, usize), pixel:Pixel) -> Vec<(usize, usize)> {
+        let id = y * self.size.0 + x;
+        self.layers[layer].data[id] = pixel;
+        self.composite[id] = self.composite_pixel(id);
+        self.mark_dirty(x, y);
+        vec![(x, y)]
+    }
+
+    fn apply_buffer(&mut self, (size, layers):Buffer) -> Vec<(usize, usize)> {
+        self.size = size;
+        self.layers = layers;
+        self.active = self.active.min(self.layers.len() - 1);
+        self.update_composite();
+        self.all_pixels()
+    }
+
+    fn apply_palette(&mut self, palette:IndexedPalette) -> Vec<(usize, usize)> {
+        self.indexed = palette;
+        Vec::new()
+    }
+
+    /// Replace all the layers of the canvas, recording the change in the current transaction.
+    fn replace_layers(&mut self, size:(usize, usize), layers:Vec<Layer>) {
+        let old_size = std::mem::replace(&mut self.size, size);
+        let old_layers = std::mem::replace(&mut self.layers, layers);
+        self.history.record_buffer((old_size, old_layers), (self.size, self.layers.clone()));
+        self.active = self.active.min(self.layers.len() - 1);
+        self.update_composite();
+    }
+
+    /// Apply the same transformation to the pixels of every layer, giving a canvas of the given
+    /// size.
+    fn transform_layers<F>(&mut self, size:(usize, usize), f:F)
+        where F : Fn(&[Pixel]) -> Vec<Pixel>
+    {
+        let layers = self.layers
+            .iter()
+            .map(|layer| layer.with_data(f(&layer.data)))
+            .collect();
+
+        self.replace_layers(size, layers);
+    }
+
+    /// Return the position of every pixel of the canvas.
+    pub fn all_pixels(&self) -> Vec<(usize, usize)> {
+        let (w, h) = self.size;
+        (0..h).flat_map(|y| (0..w).map(move |x| (x, y))).collect()
+    }
+
+    /// Create a canvas holding a copy of the given rectangle of every layer of this canvas.
+    pub fn new_from_region(&self, x:usize, y:usize, w:usize, h:usize) -> Canvas {
+        let ow = self.size.0;
+        let layers = self.layers
+            .iter()
+            .map(|layer| {
+                layer.with_data((0..h)
+                    .flat_map(|j| (0..w).map(move |i| layer.data[(y + j) * ow + x + i]))
+                    .collect())
+            })
+            .collect();
+
+        let mut region = Canvas::from_layers((w, h), layers);
+        region.active = self.active;
+        region
+    }
+
+    /// Give the canvas the size and the layers of another one.
+    pub fn replace_with(&mut self, other:Canvas) {
+        self.replace_layers(other.size, other.layers);
+    }
+
+    /// Reduce the canvas to the given rectangle.
+    pub fn crop(&mut self, x:usize, y:usize, w:usize, h:usize) {
+        let Canvas { size, layers, .. } = self.new_from_region(x, y, w, h);
+        self.replace_layers(size, layers);
+    }
+
+    /// Mirror the whole active layer left to right.
+    pub fn flip_horizontal(&mut self) {
+        let (w, h) = self.size;
+        self.flip_region_horizontal(0, 0, w, h)
+    }
+
+    /// Mirror the whole active layer top to bottom.
+    pub fn flip_vertical(&mut self) {
+        let (w, h) = self.size;
+        self.flip_region_vertical(0, 0, w, h)
+    }
+
+    /// Mirror the given rectangle of the active layer left to right.
+    pub fn flip_region_horizontal(&mut self, x:usize, y:usize, w:usize, h:usize) {
+        for j in y..y + h {
+            for i in 0..w / 2 {
+                let (a, b) = (x + i, x + w - 1 - i);
+                let (pa, pb) = (self.get_pixel(a, j), self.get_pixel(b, j));
+                self.set_pixel(a, j, pb);
+                self.set_pixel(b, j, pa);
+            }
+        }
+    }
+
+    /// Mirror the given rectangle of the active layer top to bottom.
+    pub fn flip_region_vertical(&mut self, x:usize, y:usize, w:usize, h:usize) {
+        for j in 0..h / 2 {
+            for i in x..x + w {
+                let (a, b) = (y + j, y + h - 1 - j);
+                let (pa, pb) = (self.get_pixel(i, a), self.get_pixel(i, b));
+                self.set_pixel(i, a, pb);
+                self.set_pixel(i, b, pa);
+            }
+        }
+    }
+
+    /// Rotate the canvas a quarter turn clockwise, its width and height being swapped.
+    pub fn rotate_cw(&mut self) {
+        self.rotate_with(rotated_cw)
+    }
+
+    /// Rotate the canvas a quarter turn counterclockwise, its width and height being swapped.
+    pub fn rotate_ccw(&mut self) {
+        self.rotate_with(rotated_ccw)
+    }
+
+    /// Roll the pixels of every layer by the given offsets, the ones going out of the canvas
+    /// coming back on the other side.
+    pub fn shift(&mut self, dx:isize, dy:isize) {
+        let (w, h) = self.size;
+        if w == 0 || h == 0 {
+            return
+        }
+
+        let dx = dx.rem_euclid(w as isize) as usize;
+        let dy = dy.rem_euclid(h as isize) as usize;
+        self.transform_layers((w, h), |old| {
+            let mut data = vec![(0, 0, 0, 0); w * h];
+            for y in 0..h {
+                for x in 0..w {
+                    data[(y + dy) % h * w + (x + dx) % w] = old[y * w + x];
+                }
+            }
+            data
+        });
+    }
+
+    fn rotate_with<F:Fn((usize, usize), (usize, usize)) -> (usize, usize)>(&mut self, f:F) {
+        let (w, h) = self.size;
+        self.transform_layers((h, w), |old| {
+            let mut data = vec![(0, 0, 0, 0); w * h];
+            for y in 0..h {
+                for x in 0..w {
+                    let (nx, ny) = f((w, h), (x, y));
+                    data[ny * h + nx] = old[y * w + x];
+                }
+            }
+            data
+        });
+    }
+
+    /// Change the size of the canvas. Pixels out of the new size are lost and new ones are
+    /// transparent.
+    pub fn resize(&mut self, w:usize, h:usize) {
+        let (ow, oh) = self.size;
+        self.transform_layers((w, h), |old| {
+            let mut data = vec![(0, 0, 0, 0); w * h];
+            for y in 0..h.min(oh) {
+                for x in 0..w.min(ow) {
+                    data[y * w + x] = old[y * ow + x];
+                }
+            }
+            data
+        });
+    }
+
+    /// Set the maximum number of transactions that can be undone.
+    pub fn set_history_depth(&mut self, depth:usize) {
+        self.history.set_depth(depth)
+    }
 }
 
-impl AsRef<[(u8, u8, u8)]> for Canvas {
-    fn as_ref(&self) -> &[(u8, u8, u8)] {
-        &self.data
+impl AsRef<[Pixel]> for Canvas {
+    fn as_ref(&self) -> &[Pixel] {
+        &self.composite
     }
 }
 
 impl std::ops::Deref for Canvas {
-    type Target = [(u8, u8, u8)];
+    type Target = [Pixel];
 
     fn deref(&self) -> &Self::Target {
-        &self.data
+        &self.composite
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 5x3 canvas whose pixels all differ, so that no flip leaves it as it is.
+    fn gradient() -> Canvas {
+        let (w, h) = (5, 3);
+        let data = (0..h)
+            .flat_map(|y| (0..w).map(move |x| (x as u8, y as u8)))
+            .map(|(x, y)| (x * 40, y * 70, x + y, 255 - x))
+            .collect();
+        Canvas::from_layers((w, h), vec![Layer::from_pixels("Layer 1", data)])
+    }
+
+    #[test]
+    fn flip_twice_restores_canvas() {
+        let original = gradient();
+        let mut canvas = gradient();
+
+        canvas.flip_horizontal();
+        assert_ne!(canvas.layers()[0].pixels(), original.layers()[0].pixels());
+        assert_eq!(canvas.get_pixel(0, 1), original.get_pixel(4, 1));
+        canvas.flip_horizontal();
+        assert_eq!(canvas.layers()[0].pixels(), original.layers()[0].pixels());
+
+        canvas.flip_vertical();
+        assert_ne!(canvas.layers()[0].pixels(), original.layers()[0].pixels());
+        assert_eq!(canvas.get_pixel(1, 0), original.get_pixel(1, 2));
+        canvas.flip_vertical();
+        assert_eq!(canvas.layers()[0].pixels(), original.layers()[0].pixels());
+    }
+
+    #[test]
+    fn flip_region_twice_restores_canvas() {
+        let original = gradient();
+        let mut canvas = gradient();
+
+        canvas.flip_region_horizontal(1, 0, 3, 2);
+        assert_eq!(canvas.get_pixel(1, 1), original.get_pixel(3, 1));
+        assert_eq!(canvas.get_pixel(0, 1), original.get_pixel(0, 1));
+        assert_eq!(canvas.get_pixel(1, 2), original.get_pixel(1, 2));
+        canvas.flip_region_horizontal(1, 0, 3, 2);
+        assert_eq!(canvas.layers()[0].pixels(), original.layers()[0].pixels());
+
+        canvas.flip_region_vertical(1, 1, 3, 2);
+        assert_eq!(canvas.get_pixel(2, 1), original.get_pixel(2, 2));
+        assert_eq!(canvas.get_pixel(2, 0), original.get_pixel(2, 0));
+        assert_eq!(canvas.get_pixel(4, 1), original.get_pixel(4, 1));
+        canvas.flip_region_vertical(1, 1, 3, 2);
+        assert_eq!(canvas.layers()[0].pixels(), original.layers()[0].pixels());
+    }
+
+    #[test]
+    fn invert_twice_restores_canvas() {
+        let original = gradient();
+        let mut canvas = gradient();
+
+        canvas.invert(None);
+        assert_eq!(canvas.get_pixel(1, 2), (215, 115, 252, 254));
+        canvas.invert(None);
+        assert_eq!(canvas.layers()[0].pixels(), original.layers()[0].pixels());
+    }
+
+    #[test]
+    fn grayscale_weights_channels() {
+        let data = vec![(255, 0, 0, 255), (0, 255, 0, 128), (0, 0, 255, 0), (10, 20, 30, 255)];
+        let mut canvas = Canvas::from_layers((4, 1), vec![Layer::from_pixels("Layer 1", data)]);
+
+        canvas.grayscale(None);
+        assert_eq!(canvas.get_pixel(0, 0), (76, 76, 76, 255));
+        assert_eq!(canvas.get_pixel(1, 0), (150, 150, 150, 128));
+        assert_eq!(canvas.get_pixel(2, 0), (29, 29, 29, 0));
+        assert_eq!(canvas.get_pixel(3, 0), (18, 18, 18, 255));
+    }
+
+    #[test]
+    fn undo_layer_visibility_and_opacity() {
+        let mut canvas = gradient();
+
+        canvas.begin_transaction();
+        canvas.toggle_layer_visibility();
+        canvas.commit_transaction();
+        canvas.begin_transaction();
+        canvas.set_layer_opacity(0.5);
+        canvas.commit_transaction();
+        assert!(!canvas.layers()[0].visible);
+        assert_eq!(canvas.layers()[0].opacity, 0.5);
+
+        canvas.undo();
+        assert!(!canvas.layers()[0].visible);
+        assert_eq!(canvas.layers()[0].opacity, 1.0);
+        canvas.undo();
+        assert!(canvas.layers()[0].visible);
+        canvas.redo();
+        assert!(!canvas.layers()[0].visible);
     }
 }
@@ -0,0 +1,12 @@
+in vec2 texcoord;
+
+uniform sampler2D tex;
+uniform vec4 tint;
+
+out vec4 diffuseColor;
+
+void main()
+{
+    vec4 color = texture(tex, texcoord);
+    diffuseColor = vec4(mix(color.rgb, tint.rgb, 0.5), color.a * tint.a);
+}
@@ -0,0 +1,69 @@
+use std::fmt::Write;
+use crate::canvas::Canvas;
+
+/// Number of bytes written on each line of an array, that is four pixels.
+const BYTES_PER_LINE : usize = 16;
+
+/// Write the RGBA bytes of the displayed canvas, row by row, as the lines of an array literal.
+fn array_body(canvas:&Canvas) -> String {
+    let bytes : Vec<u8> = canvas
+        .iter()
+        .flat_map(|&(r, g, b, a)| vec![r, g, b, a])
+        .collect();
+
+    let mut body = String::new();
+    for line in bytes.chunks(BYTES_PER_LINE) {
+        let line : Vec<String> = line.iter().map(|b| format!("0x{:02x},", b)).collect();
+        let _ = writeln!(body, "    {}", line.join(" "));
+    }
+
+    body
+}
+
+/// Write the canvas as Rust constants: its width, its height and its RGBA bytes.
+pub fn to_rust_array(canvas:&Canvas, name:&str) -> String {
+    let (w, h) = canvas.size();
+    format!("pub const {0}_WIDTH: usize = {1};\npub const {0}_HEIGHT: usize = {2};\n\
+             pub const {0}: [u8; {3}] = [\n{4}];\n",
+            name, w, h, w * h * 4, array_body(canvas))
+}
+
+/// Write the canvas as C definitions: its width, its height and its RGBA bytes.
+pub fn to_c_array(canvas:&Canvas, name:&str) -> String {
+    let (w, h) = canvas.size();
+    format!("#define {0}_WIDTH {1}\n#define {0}_HEIGHT {2}\n\n\
+             const unsigned char {0}[{3}] = {{\n{4}}};\n",
+            name, w, h, w * h * 4, array_body(canvas))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::Layer;
+
+    fn two_pixels() -> Canvas {
+        let data = vec![(0x01, 0x02, 0x03, 0xff), (0xa0, 0xb0, 0xc0, 0xff)];
+        Canvas::from_layers((2, 1), vec![Layer::from_pixels("Layer 1", data)])
+    }
+
+    #[test]
+    fn rust_array_of_two_pixels() {
+        assert_eq!(to_rust_array(&two_pixels(), "SPRITE"),
+                   "pub const SPRITE_WIDTH: usize = 2;\n\
+                    pub const SPRITE_HEIGHT: usize = 1;\n\
+                    pub const SPRITE: [u8; 8] = [\n    \
+                    0x01, 0x02, 0x03, 0xff, 0xa0, 0xb0, 0xc0, 0xff,\n\
+                    ];\n");
+    }
+
+    #[test]
+    fn c_array_of_two_pixels() {
+        assert_eq!(to_c_array(&two_pixels(), "SPRITE"),
+                   "#define SPRITE_WIDTH 2\n\
+                    #define SPRITE_HEIGHT 1\n\
+                    \n\
+                    const unsigned char SPRITE[8] = {\n    \
+                    0x01, 0x02, 0x03, 0xff, 0xa0, 0xb0, 0xc0, 0xff,\n\
+                    };\n");
+    }
+}
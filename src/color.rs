@@ -0,0 +1,138 @@
+/// Euclidean distance between two colors in RGB.
+pub fn distance((r1, g1, b1):(u8, u8, u8), (r2, g2, b2):(u8, u8, u8)) -> f32 {
+    let (dr, dg, db) = (r1 as f32 - r2 as f32, g1 as f32 - g2 as f32, b1 as f32 - b2 as f32);
+    (dr*dr + dg*dg + db*db).sqrt()
+}
+
+/// Index of the color of the palette closest to the given one, the first one on a tie. The
+/// palette must not be empty.
+pub fn nearest(color:(u8, u8, u8), palette:&[(u8, u8, u8)]) -> usize {
+    palette
+        .iter()
+        .enumerate()
+        .min_by(|(_, &c1), (_, &c2)| distance(color, c1).partial_cmp(&distance(color, c2)).unwrap())
+        .map(|(i, _)| i)
+        .expect("Cannot find the nearest color in an empty palette")
+}
+
+/// Parse a color written as `#RRGGBB`.
+pub fn parse_hex(s:&str) -> Option<(u8, u8, u8)> {
+    let hex = s.strip_prefix('#')?;
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None
+    }
+
+    let channel = |i:usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some((channel(0)?, channel(2)?, channel(4)?))
+}
+
+/// Write a color as `#RRGGBB`.
+pub fn to_hex((r, g, b):(u8, u8, u8)) -> String {
+    format!("#{:02X}{:02X}{:02X}", r, g, b)
+}
+
+/// Convert a color to HSV: its hue in degrees in `[0, 360)`, and its saturation and value in
+/// `[0, 1]`.
+pub fn to_hsv((r, g, b):(u8, u8, u8)) -> (f32, f32, f32) {
+    let (r, g, b) = (r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+
+    let saturation = if max == 0.0 { 0.0 } else { delta / max };
+    (hue.rem_euclid(360.0), saturation, max)
+}
+
+/// Convert a color from HSV, its hue being in degrees and its saturation and value in `[0, 1]`.
+pub fn from_hsv((h, s, v):(f32, f32, f32)) -> (u8, u8, u8) {
+    let h = h.rem_euclid(360.0) / 60.0;
+    let c = v * s;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+
+    let m = v - c;
+    let channel = |c:f32| ((c + m) * 255.0).round().max(0.0).min(255.0) as u8;
+    (channel(r), channel(g), channel(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_write_hex() {
+        assert_eq!(parse_hex("#FF8000"), Some((255, 128, 0)));
+        assert_eq!(parse_hex("#ff8000"), Some((255, 128, 0)));
+        assert_eq!(to_hex((255, 128, 0)), "#FF8000");
+        assert_eq!(parse_hex(&to_hex((1, 2, 3))), Some((1, 2, 3)));
+    }
+
+    #[test]
+    fn reject_malformed_hex() {
+        assert_eq!(parse_hex("FF8000"), None);
+        assert_eq!(parse_hex("#FF800"), None);
+        assert_eq!(parse_hex("#FF80000"), None);
+        assert_eq!(parse_hex("#GG8000"), None);
+        assert_eq!(parse_hex("#FF80é"), None);
+        assert_eq!(parse_hex("#+f+f+f"), None);
+        assert_eq!(parse_hex("#+12345"), None);
+        assert_eq!(parse_hex(""), None);
+    }
+
+    #[test]
+    fn distance_between_colors() {
+        assert_eq!(distance((10, 20, 30), (10, 20, 30)), 0.0);
+        assert_eq!(distance((0, 0, 0), (3, 4, 0)), 5.0);
+        assert_eq!(distance((0, 0, 0), (255, 0, 0)), distance((255, 0, 0), (0, 0, 0)));
+    }
+
+    #[test]
+    fn nearest_color_of_palette() {
+        let palette = [(0, 0, 0), (255, 255, 255), (255, 0, 0)];
+        assert_eq!(nearest((20, 10, 10), &palette), 0);
+        assert_eq!(nearest((200, 210, 220), &palette), 1);
+        assert_eq!(nearest((200, 30, 20), &palette), 2);
+        assert_eq!(nearest((255, 0, 0), &palette), 2);
+        // the first of two colors as close is taken
+        assert_eq!(nearest((10, 10, 10), &[(0, 0, 0), (20, 20, 20)]), 0);
+    }
+
+    #[test]
+    fn hsv_of_primary_colors() {
+        assert_eq!(to_hsv((255, 0, 0)), (0.0, 1.0, 1.0));
+        assert_eq!(to_hsv((0, 255, 0)), (120.0, 1.0, 1.0));
+        assert_eq!(to_hsv((0, 0, 255)), (240.0, 1.0, 1.0));
+        assert_eq!(to_hsv((0, 0, 0)), (0.0, 0.0, 0.0));
+        assert_eq!(from_hsv((360.0, 1.0, 1.0)), (255, 0, 0));
+        assert_eq!(from_hsv((-120.0, 1.0, 1.0)), (0, 0, 255));
+    }
+
+    #[test]
+    fn hsv_round_trip() {
+        for r in (0..=255).step_by(15) {
+            for g in (0..=255).step_by(15) {
+                for b in (0..=255).step_by(15) {
+                    let color = (r as u8, g as u8, b as u8);
+                    assert_eq!(from_hsv(to_hsv(color)), color);
+                }
+            }
+        }
+    }
+}
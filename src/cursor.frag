@@ -0,0 +1,8 @@
+out vec4 diffuseColor;
+
+void main()
+{
+    // Blended with the complement of the color below, so the border is the inverse of what it
+    // covers and can be seen over any color.
+    diffuseColor = vec4(1.0, 1.0, 1.0, 1.0);
+}
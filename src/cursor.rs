@@ -0,0 +1,53 @@
+use luminance_derive::{Semantics, Vertex};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Semantics)]
+pub enum Semantics {
+    #[sem(name="pos", repr="[f32;2]", wrapper="CursorPos")]
+    Position,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Vertex)]
+#[vertex(sem = "Semantics")]
+pub struct Vertex {
+    pos: CursorPos,
+}
+
+/// Width of the border drawn around the cursor, in canvas pixels.
+const BORDER : f32 = 0.2;
+
+/// Build the border drawn inside the cell of the cursor, to be drawn with `Mode::Triangle`, as
+/// four rectangles along the sides of the cell.
+pub fn vertice_from_cell((x, y):(usize, usize)) -> Vec<Vertex> {
+    let (x, y) = (x as f32, y as f32);
+    let sides = [
+        (x, y, x + 1.0, y + BORDER),
+        (x, y + 1.0 - BORDER, x + 1.0, y + 1.0),
+        (x, y + BORDER, x + BORDER, y + 1.0 - BORDER),
+        (x + 1.0 - BORDER, y + BORDER, x + 1.0, y + 1.0 - BORDER),
+    ];
+
+    sides
+        .iter()
+        .flat_map(|&(x1, y1, x2, y2)| {
+            vec![[x1, y1], [x2, y1], [x1, y2], [x1, y2], [x2, y2], [x2, y1]]
+        })
+        .map(|pos| Vertex { pos: CursorPos::new(pos) })
+        .collect()
+}
+
+/// Whether a blinking cursor is shown, given the time since it last moved and the duration of a
+/// blink in milliseconds. The cursor does not blink when the duration is zero.
+pub fn is_visible(elapsed_ms:u128, blink_ms:u64) -> bool {
+    blink_ms == 0 || (elapsed_ms / blink_ms as u128) % 2 == 0
+}
+
+/// Time in milliseconds until a blinking cursor is shown or hidden, given the time since it last
+/// moved and the duration of a blink in milliseconds, if it blinks.
+pub fn until_blink(elapsed_ms:u128, blink_ms:u64) -> Option<u64> {
+    if blink_ms == 0 {
+        return None
+    }
+
+    Some(blink_ms - (elapsed_ms % blink_ms as u128) as u64)
+}
@@ -0,0 +1,11 @@
+out vec4 diffuseColor;
+
+void main()
+{
+    // Every other pair of fragments is dropped so that the lines are dotted.
+    if (mod(floor(gl_FragCoord.x) + floor(gl_FragCoord.y), 4.0) < 2.0) {
+        discard;
+    }
+
+    diffuseColor = vec4(0.5, 0.5, 0.5, 0.8);
+}
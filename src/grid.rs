@@ -0,0 +1,81 @@
+use luminance_derive::{Semantics, Vertex};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Semantics)]
+pub enum Semantics {
+    #[sem(name="pos", repr="[f32;2]", wrapper="GridPos")]
+    Position,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Vertex)]
+#[vertex(sem = "Semantics")]
+pub struct Vertex {
+    pos: GridPos,
+}
+
+/// Number of canvas pixels between two lines of the grid, the step from one line to the next.
+/// The grid follows the pixels, until they get so large on screen that a line every pixel is
+/// hard to follow, then a line is drawn every 8 pixels.
+pub fn spacing(pixel_size_on_screen:f32) -> usize {
+    if pixel_size_on_screen >= 64.0 { 8 } else { 1 }
+}
+
+/// Build the lines of a grid covering a canvas of the given size, to be drawn with
+/// `Mode::Line`. The borders of the canvas are always part of the grid.
+pub fn vertice_from_size((w, h):(usize, usize), spacing:usize) -> Vec<Vertex> {
+    let (fw, fh) = (w as f32, h as f32);
+    let columns = (0..w).step_by(spacing).chain(Some(w)).map(|x| x as f32);
+    let rows = (0..h).step_by(spacing).chain(Some(h)).map(|y| y as f32);
+
+    columns
+        .flat_map(|x| vec![[x, 0.0], [x, fh]])
+        .chain(rows.flat_map(|y| vec![[0.0, y], [fw, y]]))
+        .map(|pos| Vertex { pos: GridPos::new(pos) })
+        .collect()
+}
+
+/// Build the outline of a rectangle given by its opposite corners, in canvas pixels, to be drawn
+/// with `Mode::Line`.
+pub fn vertice_from_rect((x1, y1):(f32, f32), (x2, y2):(f32, f32)) -> Vec<Vertex> {
+    [[x1, y1], [x2, y1], [x2, y1], [x2, y2], [x2, y2], [x1, y2], [x1, y2], [x1, y1]]
+        .iter()
+        .map(|&pos| Vertex { pos: GridPos::new(pos) })
+        .collect()
+}
+
+/// A line at a fixed canvas coordinate to align elements on: a horizontal guide runs along a row
+/// and a vertical one along a column.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Guide {
+    Horizontal(usize),
+    Vertical(usize),
+}
+
+/// Build the lines of the guides across a canvas of the given size, through the middle of their
+/// row or column, to be drawn with `Mode::Line`.
+pub fn vertice_from_guides(guides:&[Guide], (w, h):(usize, usize)) -> Vec<Vertex> {
+    let (fw, fh) = (w as f32, h as f32);
+    guides
+        .iter()
+        .flat_map(|guide| match *guide {
+            Guide::Horizontal(y) => vec![[0.0, y as f32 + 0.5], [fw, y as f32 + 0.5]],
+            Guide::Vertical(x) => vec![[x as f32 + 0.5, 0.0], [x as f32 + 0.5, fh]],
+        })
+        .map(|pos| Vertex { pos: GridPos::new(pos) })
+        .collect()
+}
+
+/// Return where a coordinate moved from `old` to `new` ends up when snapped to the given guide
+/// coordinates: on the nearest guide at most `distance` away which the motion goes towards, else
+/// on `new`. Guides behind the motion are ignored so that the cursor can leave a guide.
+pub fn snap<I:Iterator<Item=usize>>(old:usize, new:usize, guides:I, distance:usize) -> usize {
+    if old == new {
+        return new
+    }
+
+    guides
+        .filter(|&g| if new > old { g > old } else { g < old })
+        .filter(|&g| (g as isize - new as isize).abs() as usize <= distance)
+        .min_by_key(|&g| (g as isize - new as isize).abs())
+        .unwrap_or(new)
+}
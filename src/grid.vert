@@ -0,0 +1,9 @@
+in vec2 pos;
+
+uniform mat3 view;
+
+void main()
+{
+    vec3 fpos = vec3(pos, 1.0) * view;
+    gl_Position = vec4(fpos.x, fpos.y, 0.0, 1.0);
+}
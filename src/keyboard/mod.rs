@@ -1,5 +1,5 @@
 use glfw::Key;
-use std::collections::HashMap;
+use std::{fmt, collections::HashMap};
 
 pub mod azerty;
 
@@ -245,52 +245,25 @@ pub enum CharKey {
     Special(u32),
 }
 
+/// Names of the special keys, a key being identified by its position. `<Space>`, `<Less>` and
+/// `<More>` stand for characters rather than special keys.
+const SPECIAL_KEYS : &[&str] = &[
+    "<Esc>", "<F1>", "<F2>", "<F3>", "<F4>", "<F5>", "<F6>", "<F7>", "<F8>", "<F9>", "<F10>",
+    "<F11>", "<F12>", "<Insert>", "<Del>", "<BS>", "<Beg>", "<End>", "<PUp>", "<PDown>",
+    "<Left>", "<Down>", "<Up>", "<Right>", "<CR>", "<Space>", "<Less>", "<More>", "<Tab>",
+];
+
 impl From<&str> for CharKey {
     fn from(s:&str) -> CharKey {
         if s.len() == 1 {
             CharKey::Char(s.chars().next().unwrap())
         } else {
-            let code : u32 = match s {
-                "<Esc>" => 0,
-                "<F1>" => 1,
-                "<F2>" => 2,
-                "<F3>" => 3,
-                "<F4>" => 4,
-                "<F5>" => 5,
-                "<F6>" => 6,
-                "<F7>" => 7,
-                "<F8>" => 8,
-                "<F9>" => 9,
-                "<F10>" => 10,
-                "<F11>" => 11,
-                "<F12>" => 12,
-                "<Insert>" => 13,
-                "<Del>" => 14,
-                "<BS>" => 15,
-                "<Beg>" => 16,
-                "<End>" => 17,
-                "<PUp>" => 18,
-                "<PDown>" => 19,
-                "<Left>" => 20,
-                "<Down>" => 21,
-                "<Up>" => 22,
-                "<Right>" => 23,
-                "<CR>" => 24,
-                "<Space>" => 25,
-                "<Less>" => 26,
-                "<More>" => 27,
-                "<Tab>" => 28,
-                _ => 1000,
-            };
-
-            if code == 25 {
-                CharKey::Char(' ')
-            } else if code == 26 {
-                CharKey::Char('<')
-            } else if code == 27 {
-                CharKey::Char('>')
-            } else {
-                CharKey::Special(code)
+            match SPECIAL_KEYS.iter().position(|&name| name == s) {
+                Some(25) => CharKey::Char(' '),
+                Some(26) => CharKey::Char('<'),
+                Some(27) => CharKey::Char('>'),
+                Some(code) => CharKey::Special(code as u32),
+                None => CharKey::Special(1000),
             }
         }
     }
@@ -302,59 +275,207 @@ pub struct CharKeyMod {
     pub mods:ModSet,
 }
 
+/// Reason why a key such as `<C-x>` cannot be parsed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum KeyParseError {
+    Empty,
+    Unterminated(String),
+    UnknownModifier(char),
+    UnknownKey(String),
+}
+
+impl fmt::Display for KeyParseError {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyParseError::Empty => write!(f, "no key given"),
+            KeyParseError::Unterminated(s) => write!(f, "missing '>' in \"{}\"", s),
+            KeyParseError::UnknownModifier(c) => write!(f, "unknown modifier {}-", c),
+            KeyParseError::UnknownKey(s) => write!(f, "unknown key \"{}\"", s),
+        }
+    }
+}
+
+impl CharKeyMod {
+    /// Parse a key: a single character, an uppercase letter implying shift, or a name between
+    /// angle brackets preceded by modifiers, such as `<Esc>` or `<C-S-x>`.
+    pub fn try_from(s:&str) -> Result<CharKeyMod, KeyParseError> {
+        let mut mods = ModSet::empty();
+        let mut chars = s.chars();
+
+        match (chars.next(), chars.next()) {
+            (None, _) => return Err(KeyParseError::Empty),
+            (Some(c), None) => {
+                if c.is_ascii_uppercase() {
+                    mods.set(Mod::Shift);
+                }
+                return Ok(CharKeyMod { key:CharKey::Char(c), mods })
+            },
+            (Some('<'), Some(_)) if !s.ends_with('>') => {
+                return Err(KeyParseError::Unterminated(s.to_string()))
+            },
+            (Some('<'), Some(_)) => {},
+            _ => return Err(KeyParseError::UnknownKey(s.to_string())),
+        }
+
+        // the modifiers are the letters followed by a dash, what remains is the name of the key
+        let mut name = &s[1..s.len() - 1];
+        while name.chars().nth(1) == Some('-') {
+            let c = name.chars().next().unwrap();
+            mods.set(match c {
+                'S' => Mod::Shift,
+                'C' => Mod::Control,
+                'A' => Mod::Alt,
+                c => return Err(KeyParseError::UnknownModifier(c)),
+            });
+            name = &name[c.len_utf8() + 1..];
+        }
+
+        // a letter is uppercase exactly when shift is held, as the keyboard layout reports it
+        let mut name_chars = name.chars();
+        let key = match (name_chars.next(), name_chars.next()) {
+            (None, _) => return Err(KeyParseError::Empty),
+            (Some(c), None) if c.is_ascii_uppercase() => {
+                mods.set(Mod::Shift);
+                CharKey::Char(c)
+            },
+            (Some(c), None) if mods.is_set(Mod::Shift) => CharKey::Char(c.to_ascii_uppercase()),
+            (Some(c), None) => CharKey::Char(c),
+            _ => match CharKey::from(format!("<{}>", name).as_ref()) {
+                CharKey::Special(1000) => return Err(KeyParseError::UnknownKey(s.to_string())),
+                key => key,
+            },
+        };
+
+        Ok(CharKeyMod { key, mods })
+    }
+}
+
+/// Write a key the way `CharKeyMod::try_from` parses it, such as `x`, `X` or `<C-A-x>`. AltGr is
+/// not written, the character it gives standing for it.
+impl fmt::Display for CharKeyMod {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        let name = match self.key {
+            CharKey::Char(' ') => "Space".to_string(),
+            CharKey::Char('<') => "Less".to_string(),
+            CharKey::Char('>') => "More".to_string(),
+            CharKey::Char(c) => c.to_string(),
+            CharKey::Special(code) => SPECIAL_KEYS
+                .get(code as usize)
+                .map(|name| name.trim_start_matches('<').trim_end_matches('>').to_string())
+                .unwrap_or_else(|| "?".to_string()),
+        };
+
+        // the shift of an uppercase letter is implied by the letter
+        let implied_shift = match self.key {
+            CharKey::Char(c) => c.is_ascii_uppercase(),
+            CharKey::Special(_) => false,
+        };
+        let mut prefix = String::new();
+        if self.mods.is_set(Mod::Control) {
+            prefix.push_str("C-");
+        }
+        if self.mods.is_set(Mod::Alt) {
+            prefix.push_str("A-");
+        }
+        if self.mods.is_set(Mod::Shift) && !implied_shift {
+            prefix.push_str("S-");
+        }
+
+        if prefix.is_empty() && name.chars().count() == 1 {
+            write!(f, "{}", name)
+        } else {
+            write!(f, "<{}{}>", prefix, name)
+        }
+    }
+}
+
+/// Return the modifiers held according to the flags of a GLFW key event. AltGr is not among them,
+/// GLFW reporting it as Alt.
+impl From<glfw::Modifiers> for ModSet {
+    fn from(modifiers:glfw::Modifiers) -> ModSet {
+        let mut set = ModSet::empty();
+        if modifiers.contains(glfw::Modifiers::Shift) {
+            set.set(Mod::Shift);
+        }
+        if modifiers.contains(glfw::Modifiers::Control) {
+            set.set(Mod::Control);
+        }
+        if modifiers.contains(glfw::Modifiers::Alt) {
+            set.set(Mod::Alt);
+        }
+        set
+    }
+}
+
+/// Parse a key known to be valid, as `CharKeyMod::try_from` does. Keys typed by the user must be
+/// parsed with `try_from` instead, as an invalid key makes this panic.
 impl From<&str> for CharKeyMod {
     fn from(s:&str) -> CharKeyMod {
-        let mut set = ModSet::empty();
-        if s.len() == 1 {
-            if s.chars().next().unwrap().is_ascii_uppercase() {
-                set.set(Mod::Shift)
-            }
+        CharKeyMod::try_from(s).unwrap_or_else(|e| panic!("Invalid key \"{}\": {}", s, e))
+    }
+}
 
-            CharKeyMod {
-                key:s.into(),
-                mods:set,
-            }
-        } else {
-            let mut chars = s.chars();
-            let mut string = String::new();
-
-            chars.next();
-
-            while let Some(mut c) = chars.next() {
-                if c == 'S' || c == 'C' || c == 'A' {
-                    let nc = chars.by_ref().next().unwrap();
-                    if nc == '-' {
-                        set.set(match c {
-                            'S' => Mod::Shift,
-                            'C' => Mod::Control,
-                            'A' => Mod::Alt,
-                            _ => unreachable!(),
-                        });
-                        continue
-                    } else {
-                        string.push(c);
-                        c = nc;
-                    }
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                if c == '>' { break }
+    fn with(mods:&[Mod]) -> ModSet {
+        let mut set = ModSet::empty();
+        for &m in mods {
+            set.set(m);
+        }
+        set
+    }
 
-                string.push(c)
-            }
+    #[test]
+    fn parse_valid_keys() {
+        let parsed = |s| CharKeyMod::try_from(s).unwrap();
+
+        assert_eq!(parsed("x"), CharKeyMod { key: CharKey::Char('x'), mods: ModSet::empty() });
+        assert_eq!(parsed("X"), CharKeyMod { key: CharKey::Char('X'), mods: ModSet::shift() });
+        assert_eq!(parsed(":"), CharKeyMod { key: CharKey::Char(':'), mods: ModSet::empty() });
+        let special = |code| CharKeyMod { key: CharKey::Special(code), mods: ModSet::empty() };
+        assert_eq!(parsed("<Esc>"), special(0));
+        assert_eq!(parsed("<Left>"), special(20));
+        assert_eq!(parsed("<C-S-x>"), CharKeyMod {
+            key: CharKey::Char('X'),
+            mods: with(&[Mod::Control, Mod::Shift]),
+        });
+    }
 
-            if string.len() != 1 {
-                string = format!("<{}>", string);
-            } else {
-                if s.chars().next().unwrap().is_ascii_uppercase() {
-                    set.set(Mod::Shift)
-                }
-            }
+    #[test]
+    fn parse_malformed_keys() {
+        let error = |s| CharKeyMod::try_from(s).unwrap_err();
 
-            let sref : &str = string.as_ref();
-            CharKeyMod {
-                key:sref.into(),
-                mods:set,
-            }
+        assert_eq!(error(""), KeyParseError::Empty);
+        assert_eq!(error("<C-"), KeyParseError::Unterminated("<C-".to_string()));
+        assert_eq!(error("<>"), KeyParseError::Empty);
+        assert_eq!(error("<Q-x>"), KeyParseError::UnknownModifier('Q'));
+        assert_eq!(error("ab"), KeyParseError::UnknownKey("ab".to_string()));
+        assert_eq!(error("<Foo>"), KeyParseError::UnknownKey("<Foo>".to_string()));
+    }
+
+    #[test]
+    fn glfw_keys_round_trip() {
+        let layout = azerty::layout();
+        let keys = [
+            ("<C-x>", Key::X, glfw::Modifiers::Control),
+            ("<A-x>", Key::X, glfw::Modifiers::Alt),
+            ("<C-A-x>", Key::X, glfw::Modifiers::Control | glfw::Modifiers::Alt),
+            ("<S-Left>", Key::Left, glfw::Modifiers::Shift),
+            ("X", Key::X, glfw::Modifiers::Shift),
+            ("<Space>", Key::Space, glfw::Modifiers::empty()),
+            ("<Less>", Key::World1, glfw::Modifiers::empty()),
+        ];
+
+        for &(name, key, modifiers) in keys.iter() {
+            let mods = ModSet::from(modifiers);
+            let typed = CharKeyMod { key: layout.translate(&(key, mods)).unwrap(), mods };
+            let parsed = CharKeyMod::try_from(name).unwrap();
+
+            assert_eq!(typed, parsed, "{}", name);
+            assert_eq!(typed.to_string(), name);
+            assert_eq!(CharKeyMod::try_from(&typed.to_string()), Ok(typed));
         }
     }
 }
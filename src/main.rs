@@ -1,64 +1,339 @@
+mod background;
 mod bitmap2d;
 mod canvas;
+mod codegen;
+mod color;
+mod cursor;
+mod grid;
 mod keyboard;
 mod maths;
+mod palette;
+mod project;
+mod raster;
+mod rng;
 mod selection;
+mod swatch;
 mod text;
 mod ui;
 
 use std::collections::{HashSet, HashMap};
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
 
-use image::{open, DynamicImage};
+use glm::Mat3;
+use image::{open, ImageFormat};
 
 use luminance::{
     context::GraphicsContext,
-    pipeline::PipelineState,
+    pipeline::{PipelineState, Viewport},
     shader::program::Program,
     render_state::{RenderState},
-    tess::{Mode, TessBuilder},
-    texture::{Sampler, Wrap, MinFilter, MagFilter, Texture, Dim2, GenMipmaps},
-    pixel::{NormRGB8UI, NormRGBA8UI},
+    tess::{Mode, Tess, TessBuilder},
+    texture::{Sampler, Wrap, MinFilter, MagFilter, Texture, Dim2, GenMipmaps, TextureError},
+    pixel::NormRGBA8UI,
     blending::{Factor, Equation},
 };
 
-use luminance_glfw::{Surface, GlfwSurface, WindowDim, WindowOpt, WindowEvent};
+use luminance_glfw::{Surface, GlfwSurface, WindowDim, WindowOpt, WindowEvent, Action, MouseButton};
 
 use crate::bitmap2d::*;
-use crate::canvas::{Canvas, ShaderInterface, Semantics, TexPosition, Vertex, VertexPosition};
-use crate::keyboard::CharKeyMod;
+use crate::canvas::{Canvas, Pixel, ShaderInterface, Semantics, TexPosition, Vertex, VertexPosition};
+use crate::keyboard::{CharKey, CharKeyMod};
 use crate::maths::*;
 use crate::selection as sel;
 use crate::ui::*;
 
 struct UiState {
-    palette: HashMap<CharKeyMod, (u8, u8, u8)>,
+    palette: palette::Palette,
+    // current color, and the entry of the palette it was last cycled to
+    color: (u8, u8, u8),
+    palette_index: usize,
+    // colors made current, stepped through without touching the history of the canvas
+    color_history: palette::ColorHistory,
+    // colors painted with lately, the latest first
+    recent_colors: Vec<(u8, u8, u8)>,
+    // opacity the current color is painted with, between 0 and 1
+    opacity: f32,
+    // whether painting is mirrored left to right and top to bottom
+    mirror: (bool, bool),
+    // size of the brush painting in insertion mode, and whether it is round rather than square
+    brush: (usize, bool),
     must_resize: bool,
+    // inverse of the size of the window, mapping screen coordinates to the viewport
     scale: (f32, f32),
     zoom: f32,
     center: (f32, f32),
     canvas: Canvas,
     visual_type: VisualType,
+    // size of the window in screen coordinates, which the pointer positions are given in, rather
+    // than in pixels of its framebuffer, which are more numerous on HiDPI screens
     window_size: (f32, f32),
-    selection: HashSet<(usize, usize)>,
+    // ratio between the framebuffer pixels and the screen coordinates
+    content_scale: f32,
+    // size of the framebuffer of the window in pixels, which the panes are laid out in
+    framebuffer_size: (u32, u32),
+    selection: sel::Selection,
+    saved_selections: HashMap<String, HashSet<(usize, usize)>>,
+    grab: Option<Grab>,
+    // font file the main loop must load and use for the status line
+    font_to_load: Option<String>,
+    // text the main loop must draw on the canvas at the cursor, with the status line font
+    text_to_stamp: Option<String>,
+    // resolution the main loop must render the text with
+    text_resolution: Option<u32>,
+    // directory the main loop must compile the shaders from, falling back to the built-in ones
+    shader_dir_to_load: Option<String>,
+    // atlas image of the selection borders the main loop must load, and whether it was asked for,
+    // the default one being silently replaced by the generated atlas when it cannot be loaded
+    selector_to_load: Option<(String, bool)>,
+    // maximum number of frames drawn per second, without limit if zero
+    fps: u32,
+    must_redraw: bool,
+    // color the viewport is cleared with, around the canvas
+    background: [f32; 4],
+    // duration of a blink of the cursor in milliseconds, not blinking if zero
+    blink: u64,
+    // generator of the random values of the noise, seeded with `:set seed`
+    rng: rng::Rng,
+    // seconds of activity between two writes of the canvas to the swap file, never if zero
+    autosave: u64,
+    // swap file left by a previous session, loaded by `:recover`
+    swap_to_recover: Option<(String, Option<String>)>,
+    file: Option<String>,
+    must_rebuild_canvas: bool,
+    clipboard: Clipboard,
+    grid: bool,
+    // lines to align elements on, and whether the cursor snaps to them
+    guides: Vec<grid::Guide>,
+    snap: bool,
+    // whether the colors of the palette are shown along the bottom of the window
+    swatches: bool,
+    // whether the ruler mode draws the line it measures over the canvas
+    ruler_line: bool,
+    // whether a small view of the whole canvas is shown in the top-left corner of the window
+    minimap: bool,
+    // whether the canvas is repeated around itself, to check that it tiles seamlessly
+    tile: bool,
+    checker: background::Checker,
+    // position of the mouse pointer in the window, whether its left button is held down, and the
+    // pixel it was last on while held down
+    pointer: (f32, f32),
+    painting: bool,
+    pointer_pixel: Option<(usize, usize)>,
+    // whether the middle button is held down, and how far the pointer moved since the last frame
+    panning: bool,
+    pointer_delta: (f32, f32),
+    // number of frames the canvas is split into from left to right, and their playback if any
+    frames: usize,
+    playback: Option<Playback>,
+    // frame being edited, and whether the frames around it are shown over it
+    frame: usize,
+    onion: bool,
+    // the open canvases, the one being edited being in the fields above rather than in its slot
+    buffers: Vec<Buffer>,
+    buffer: usize,
+    // the other pane when the window is split vertically, the edited canvas being in the focused
+    // one
+    split: Option<Split>,
 }
 
+/// The pane of a vertically split window which does not have the focus: the buffer it shows, on
+/// which side of the window the focused pane is, and the zoom and the center of its view.
+#[derive(Clone, Copy, Debug)]
+struct Split {
+    buffer: usize,
+    focus_left: bool,
+    zoom: f32,
+    center: (f32, f32),
+}
+
+/// A canvas open besides the one being edited, with the file it comes from, its selection and
+/// the position of the cursor on it, and what else belongs to the canvas rather than to the
+/// window: its frames, its saved selections, its guides and its marks.
+struct Buffer {
+    canvas: Canvas,
+    file: Option<String>,
+    selection: sel::Selection,
+    cursor: (usize, usize),
+    frames: usize,
+    frame: usize,
+    playback: Option<Playback>,
+    onion: bool,
+    saved_selections: HashMap<String, HashSet<(usize, usize)>>,
+    guides: Vec<grid::Guide>,
+    marks: HashMap<char, (usize, usize)>,
+}
+
+impl Buffer {
+    /// A blank 16x16 canvas, not coming from any file.
+    fn new() -> Self {
+        Self {
+            canvas: Canvas::new(16, 16),
+            file: None,
+            selection: sel::Selection::default(),
+            cursor: (0, 0),
+            frames: 1,
+            frame: 0,
+            playback: None,
+            onion: false,
+            saved_selections: HashMap::new(),
+            guides: Vec::new(),
+            marks: HashMap::new(),
+        }
+    }
+}
+
+/// Playback of the frames of the canvas: the time each frame is shown, the time the current one
+/// has been shown and its index.
+struct Playback {
+    interval: Duration,
+    elapsed: Duration,
+    frame: usize,
+}
+
+impl Playback {
+    /// Move to the next frames once the current one has been shown long enough.
+    fn advance(&mut self, dt:Duration, frames:usize) {
+        self.elapsed += dt;
+        while self.elapsed >= self.interval {
+            self.elapsed -= self.interval;
+            self.frame = (self.frame + 1) % frames.max(1);
+        }
+    }
+}
+
+/// Put the canvas being edited back in its slot and edit the one of the given buffer instead.
+/// Grabbed pixels are dropped where they are.
+fn switch_buffer(ui:&mut Ui<UiState>, state:&mut UiState, n:usize) {
+    if n == state.buffer || n >= state.buffers.len() {
+        return
+    }
+
+    if state.grab.take().is_some() {
+        state.canvas.commit_transaction();
+    }
+
+    let (x, y) = ui.cursor();
+    for &i in &[state.buffer, n] {
+        let buffer = &mut state.buffers[i];
+        std::mem::swap(&mut state.canvas, &mut buffer.canvas);
+        std::mem::swap(&mut state.file, &mut buffer.file);
+        std::mem::swap(&mut state.selection, &mut buffer.selection);
+        std::mem::swap(&mut state.frames, &mut buffer.frames);
+        std::mem::swap(&mut state.frame, &mut buffer.frame);
+        std::mem::swap(&mut state.playback, &mut buffer.playback);
+        std::mem::swap(&mut state.onion, &mut buffer.onion);
+        std::mem::swap(&mut state.saved_selections, &mut buffer.saved_selections);
+        std::mem::swap(&mut state.guides, &mut buffer.guides);
+        std::mem::swap(ui.marks_mut(), &mut buffer.marks);
+    }
+    state.buffers[state.buffer].cursor = (x, y);
+    state.buffer = n;
+    sync_snap(ui, state);
+
+    let (x, y) = state.buffers[n].cursor;
+    ui.set_cursor(x, y);
+    state.must_rebuild_canvas = true;
+    buffer_message(ui, state);
+}
+
+/// Show the number, the file and the size of the buffer being edited.
+fn buffer_message(ui:&mut Ui<UiState>, state:&UiState) {
+    let (w, h) = state.canvas.size();
+    let name = state.file.as_deref().unwrap_or("[No Name]");
+    let count = state.buffers.len();
+    ui.set_message(format!("{}/{} \"{}\" {}x{}", state.buffer + 1, count, name, w, h));
+}
+
+/// Size of a canvas pixel on screen, without zoom.
+const PIXEL_SIZE : f32 = 16.0;
+
+/// Size of the longest side of the minimap, and distance of the minimap to the top-left corner of
+/// the window, in screen pixels.
+const MINIMAP_SIZE : f32 = 128.0;
+const MINIMAP_MARGIN : f32 = 10.0;
+
+/// Number of recently used colors remembered, picked with the keys 1 to 9 in insertion mode.
+const RECENT_COLORS : usize = 9;
+
+/// Number of colors made current that can be stepped back through.
+const COLOR_HISTORY : usize = 32;
+
+/// Distance of the swatch bar to the left and to the bottom of the window, in screen pixels,
+/// leaving room for the status line under it.
+const SWATCH_MARGIN : (f32, f32) = (10.0, 40.0);
+
+/// Largest distance in canvas pixels from which the cursor snaps to a guide.
+const SNAP_DISTANCE : usize = 2;
+
+/// Return the size of a canvas pixel in the minimap, if it is shown.
+fn minimap_scale(state:&UiState) -> Option<f32> {
+    let (w, h) = state.canvas.size();
+    if state.minimap && w > 0 && h > 0 {
+        Some(MINIMAP_SIZE / w.max(h) as f32)
+    } else {
+        None
+    }
+}
+
+/// Convert a position in the window into a position on the canvas if it is over the minimap.
+fn minimap_to_canvas(state:&UiState, (x, y):(f32, f32)) -> Option<(f32, f32)> {
+    let k = minimap_scale(state)?;
+    let (w, h) = state.canvas.size();
+    let (cx, cy) = ((x - MINIMAP_MARGIN) / k, (y - MINIMAP_MARGIN) / k);
+    if cx < 0.0 || cy < 0.0 || cx >= w as f32 || cy >= h as f32 {
+        return None
+    }
+
+    Some((cx, cy))
+}
+
+/// What the highlighted pixels depend on: the generations of the canvas and of the selection,
+/// and in visual mode, the type of the highlighting, the cursor, the anchor and whether the
+/// ruler draws its line.
+type SelectKey = (u64, u64, Option<(VisualType, (usize, usize), (usize, usize), bool)>);
+
+/// Yanked pixels and their colors, positioned relatively to the top-left corner of the selection.
+type Clipboard = Vec<((usize, usize), Pixel)>;
+
+#[derive(Clone, Copy, PartialEq)]
 enum VisualType {
     Square,
+    SquareOutline,
     Circle,
+    Line,
+    Rows,
+    Columns,
+    // measures the distance from the anchor to the cursor, highlighting no pixel to act on
+    Ruler,
 }
 
 impl VisualType {
-    fn select_pixels<T:BitMap2D>(&self, set:&mut T, (x1, y1):(usize, usize), (x2, y2):(usize, usize)) {
+    /// Return the top-left and bottom-right corners of the highlighted area, the row and column
+    /// modes spanning the whole canvas of the given size on one axis.
+    fn get_selection(&self, ui:&Ui<UiState>, (w, h):(usize, usize))
+        -> ((usize, usize), (usize, usize)) {
+        let ((x1, y1), (x2, y2)) = ui.get_selection();
         match self {
-            VisualType::Square => {
-                (x1..x2+1)
+            VisualType::Rows => ((0, y1), (w.saturating_sub(1), y2)),
+            VisualType::Columns => ((x1, 0), (x2, h.saturating_sub(1))),
+            _ => ((x1, y1), (x2, y2)),
+        }
+    }
+
+    fn select_pixels<T:BitMap2D>(&self, set:&mut T, ui:&Ui<UiState>, size:(usize, usize)) {
+        let ((x1, y1), (x2, y2)) = self.get_selection(ui, size);
+        match self {
+            VisualType::Square | VisualType::Rows | VisualType::Columns => {
+                raster::rectangle(x1, y1, x2, y2)
                     .into_iter()
-                    .flat_map(|x| {
-                        (y1..y2+1)
-                            .into_iter()
-                            .map(move |y| (x, y))
-                    })
+                    .for_each(|(x, y)| { set.set_bit(x, y); });
+            },
+            VisualType::SquareOutline => {
+                raster::rectangle(x1, y1, x2, y2)
+                    .into_iter()
+                    .filter(|&(x, y)| x == x1 || x == x2 || y == y1 || y == y2)
                     .for_each(|(x, y)| { set.set_bit(x, y); });
             },
             VisualType::Circle => {
@@ -79,166 +354,2199 @@ impl VisualType {
                         }
                     });
             },
+            VisualType::Line => {
+                let (ax, ay) = ui.get_anchor();
+                let (cx, cy) = ui.cursor();
+                raster::line(ax as isize, ay as isize, cx as isize, cy as isize)
+                    .into_iter()
+                    .for_each(|(x, y)| { set.set_bit(x, y); });
+            },
+            VisualType::Ruler => {},
+        }
+    }
+}
+
+/// Return the horizontal and vertical offsets from the anchor of the visual mode to the cursor,
+/// and the distance between them.
+fn ruler_measure(ui:&Ui<UiState>) -> (isize, isize, f32) {
+    let (ax, ay) = ui.get_anchor();
+    let (cx, cy) = ui.cursor();
+    let (dx, dy) = (cx as isize - ax as isize, cy as isize - ay as isize);
+    (dx, dy, ((dx * dx + dy * dy) as f32).sqrt())
+}
+
+/// Return the pixels of the line drawn over the canvas from the anchor to the cursor in ruler
+/// mode, which no action applies to.
+fn ruler_line(ui:&Ui<UiState>) -> HashSet<(usize, usize)> {
+    let (ax, ay) = ui.get_anchor();
+    let (cx, cy) = ui.cursor();
+    raster::line(ax as isize, ay as isize, cx as isize, cy as isize).into_iter().collect()
+}
+
+/// Return the pixels an action applies to: the highlighted pixels in visual mode, else the
+/// selection if any, else the cursor.
+fn active_selection(ui:&Ui<UiState>, state:&UiState) -> HashSet<(usize, usize)> {
+    if ui.get_mode() == ui::Mode::Visual {
+        let mut set = HashSet::new();
+        state.visual_type.select_pixels(&mut set, ui, state.canvas.size());
+        set
+    } else if state.selection.is_empty() {
+        [ui.cursor()].iter().cloned().collect()
+    } else {
+        state.selection.clone()
+    }
+}
+
+/// Pixels being moved: their positions and colors when they were grabbed, how far they have been
+/// moved, and the colors to give back to the pixels they leave, the grabbed pixels leaving
+/// transparent ones behind them.
+struct Grab {
+    pixels: Vec<((usize, usize), Pixel)>,
+    offset: (isize, isize),
+    background: HashMap<(usize, usize), Pixel>,
+}
+
+/// Move the cursor by one pixel, wrapping around the canvas or stopping at its border.
+fn step_cursor(ui:&mut Ui<UiState>, canvas:&Canvas, dx:isize, dy:isize) {
+    let (w, h) = canvas.size();
+    if ui.wraps() {
+        ui.wrapping_displace(dx, dy, w, h);
+    } else {
+        ui.clamping_displace(dx, dy, w, h);
+    }
+}
+
+/// Move the grabbed pixels, the selection and the cursor by one pixel, unless the pixels would get
+/// out of the frame being edited.
+fn move_grabbed(ui:&mut Ui<UiState>, state:&mut UiState, dx:isize, dy:isize) {
+    let (w, h) = state.canvas.size();
+    let (x0, fw) = frame_columns(state);
+    let grab = match state.grab.as_mut() {
+        Some(grab) => grab,
+        None => return,
+    };
+
+    let (ox, oy) = grab.offset;
+    let moved = |((x, y), _):&((usize, usize), Pixel), (ox, oy):(isize, isize)| {
+        (x as isize + ox, y as isize + oy)
+    };
+
+    let fits = grab.pixels.iter().all(|p| {
+        let (x, y) = moved(p, (ox + dx, oy + dy));
+        x >= x0 as isize && y >= 0 && x < (x0 + fw) as isize && y < h as isize
+    });
+    if !fits {
+        return
+    }
+
+    // give back their colors to the pixels left, then draw the grabbed pixels at their new place
+    for p in &grab.pixels {
+        let (x, y) = moved(p, (ox, oy));
+        let pos = (x as usize, y as usize);
+        state.canvas.set_pixel(pos.0, pos.1, grab.background[&pos]);
+    }
+
+    grab.offset = (ox + dx, oy + dy);
+    for p in &grab.pixels {
+        let (x, y) = moved(p, grab.offset);
+        let pos = (x as usize, y as usize);
+        let canvas = &state.canvas;
+        grab.background.entry(pos).or_insert_with(|| canvas.get_pixel(pos.0, pos.1));
+        state.canvas.set_pixel(pos.0, pos.1, p.1);
+    }
+
+    state.selection = grab.pixels
+        .iter()
+        .map(|p| moved(p, grab.offset))
+        .map(|(x, y)| (x as usize, y as usize))
+        .collect();
+    ui.clamping_displace(dx, dy, w, h);
+}
+
+/// Set operations combining the pixels highlighted in visual mode with the selection.
+enum SelectionOp {
+    Union,
+    Intersection,
+    Difference,
+}
+
+/// Combine the pixels highlighted in visual mode with the selection, and leave visual mode.
+fn combine_selection(ui:&mut Ui<UiState>, state:&mut UiState, op:SelectionOp) {
+    let highlighted = active_selection(ui, state);
+    let selection = &state.selection;
+    state.selection = match op {
+        SelectionOp::Union => selection.union(&highlighted).cloned().collect(),
+        SelectionOp::Intersection => selection.intersection(&highlighted).cloned().collect(),
+        SelectionOp::Difference => selection.difference(&highlighted).cloned().collect(),
+    };
+
+    ui.set_mode(ui::Mode::Normal);
+}
+
+/// Change the zoom, moving the view so that the given point of the canvas stays at the same place
+/// on screen.
+fn zoom_around(state:&mut UiState, zoom:f32, (px, py):(f32, f32)) {
+    // below this zoom, the canvas would be too small to be seen
+    const MIN_ZOOM : f32 = 0.1;
+
+    // a point p of the canvas is drawn at scale(zoom) * translate(center) * p, so keeping it in
+    // place means old_zoom * (p + old_center) = zoom * (p + center)
+    let zoom = zoom.max(MIN_ZOOM);
+    let ratio = state.zoom / zoom;
+    let (cx, cy) = state.center;
+    state.center = (ratio * (px + cx) - px, ratio * (py + cy) - py);
+    state.zoom = zoom;
+}
+
+/// Return the left border of the focused pane and the size of the panes, in screen coordinates.
+/// The window is a single pane unless it is split.
+fn focused_pane(state:&UiState) -> (f32, (f32, f32)) {
+    let (w, h) = state.window_size;
+    match state.split {
+        Some(Split { focus_left: true, .. }) => (0.0, (w / 2.0, h)),
+        Some(Split { focus_left: false, .. }) => (w / 2.0, (w / 2.0, h)),
+        None => (0.0, (w, h)),
+    }
+}
+
+/// Build the view a canvas is drawn with in a pane of the given size, in screen coordinates.
+fn view_matrix(zoom:f32, (cx, cy):(f32, f32), (w, h):(f32, f32)) -> Mat3 {
+    scale(PIXEL_SIZE * zoom / w, -PIXEL_SIZE * zoom / h) * translate(cx, cy)
+}
+
+/// Convert a position in the window, in screen pixels from its top-left corner, into a position
+/// on the canvas of the focused pane, inverting the view the canvas is drawn with.
+fn screen_to_canvas(state:&UiState, (x, y):(f32, f32)) -> (f32, f32) {
+    let (x0, (w, h)) = focused_pane(state);
+    let (nx, ny) = (2.0 * (x - x0) / w - 1.0, 1.0 - 2.0 * y / h);
+    let view = view_matrix(state.zoom, state.center, (w, h));
+    inverse(view).map_or((0.0, 0.0), |inv| transform_point(inv, (nx, ny)))
+}
+
+/// Move the view by the distance the pointer moved since the last frame, so that the canvas
+/// follows the pointer. The cursor and the selection are left untouched.
+fn pan_view(state:&mut UiState) {
+    let (dx, dy) = std::mem::replace(&mut state.pointer_delta, (0.0, 0.0));
+    if !state.panning || (dx == 0.0 && dy == 0.0) {
+        return
+    }
+
+    let (w, h) = state.window_size;
+    let sx = state.scale.0 * PIXEL_SIZE * state.zoom;
+    let sy = -state.scale.1 * PIXEL_SIZE * state.zoom;
+    state.center.0 += 2.0 * dx / w / sx;
+    state.center.1 -= 2.0 * dy / h / sy;
+}
+
+/// Return the pixel of the canvas under the mouse pointer, if any. Only the focused pane is
+/// looked at.
+fn pixel_under_pointer(state:&UiState) -> Option<(usize, usize)> {
+    let (x0, (w, _)) = focused_pane(state);
+    if state.pointer.0 < x0 || state.pointer.0 >= x0 + w {
+        return None
+    }
+
+    let (x, y) = screen_to_canvas(state, state.pointer);
+    let (w, h) = state.canvas.size();
+    if x < 0.0 || y < 0.0 || x >= w as f32 || y >= h as f32 {
+        return None
+    }
+
+    Some((x as usize, y as usize))
+}
+
+/// Move the cursor along its row or column to the first pixel whose color differs from the one
+/// under the cursor. Past the border of the canvas, the search goes on from the other border if
+/// the cursor wraps, else the cursor stops at the border.
+fn jump_to_color_change(ui:&mut Ui<UiState>, canvas:&Canvas, (dx, dy):(isize, isize)) {
+    let (w, h) = canvas.size();
+    let (mut x, mut y) = ui.cursor();
+    let color = canvas.get_pixel_color(x, y);
+    let steps = if dx != 0 { w } else { h };
+
+    for _ in 1..steps {
+        let (nx, ny) = (x as isize + dx, y as isize + dy);
+        let inside = nx >= 0 && ny >= 0 && nx < w as isize && ny < h as isize;
+        if !inside && !ui.wraps() {
+            break
+        }
+
+        x = nx.rem_euclid(w as isize) as usize;
+        y = ny.rem_euclid(h as isize) as usize;
+        if canvas.get_pixel_color(x, y) != color {
+            break
+        }
+    }
+
+    ui.set_cursor(x, y);
+}
+
+/// Paint the set pixels of a bitmap with the current color as a single modification, the top-left
+/// corner of the bitmap being on the cursor. Pixels out of the edited frame are dropped.
+fn stamp<B:BitMap2D>(ui:&Ui<UiState>, state:&mut UiState, bitmap:&B, (bw, bh):(usize, usize)) {
+    let (cx, cy) = ui.cursor();
+    let (_, h) = state.canvas.size();
+    let (x0, w) = frame_columns(state);
+
+    state.canvas.begin_transaction();
+    for (x, y) in raster::rectangle(0, 0, bw.saturating_sub(1), bh.saturating_sub(1)) {
+        let inside = cx + x >= x0 && cx + x < x0 + w && cy + y < h;
+        if bw > 0 && bh > 0 && bitmap.get_bit(x, y) && inside {
+            state.canvas.set_pixel_color_rgb(cx + x, cy + y, state.color);
+        }
+    }
+    state.canvas.commit_transaction();
+}
+
+/// Return the first column and the width of the frame being edited, that is the whole canvas
+/// when it is not split into frames.
+fn frame_columns(state:&UiState) -> (usize, usize) {
+    let width = state.canvas.size().0 / state.frames.max(1);
+    (state.frame * width, width)
+}
+
+/// Keep only the positions in the frame being edited, the other frames being left as they are.
+fn in_frame(state:&UiState, mut set:HashSet<(usize, usize)>) -> HashSet<(usize, usize)> {
+    let (x0, w) = frame_columns(state);
+    set.retain(|&(x, _)| x >= x0 && x < x0 + w);
+    set
+}
+
+/// Return the pixels the filters apply to: the selection, else the whole canvas, given as no
+/// region. When the canvas is split into frames, only the pixels of the edited one are given.
+fn filter_region(state:&UiState) -> Option<HashSet<(usize, usize)>> {
+    match (state.selection.is_empty(), state.frames > 1) {
+        (true, false) => None,
+        (false, false) => Some(state.selection.clone()),
+        (true, true) => Some(in_frame(state, state.canvas.all_pixels().into_iter().collect())),
+        (false, true) => Some(in_frame(state, state.selection.clone())),
+    }
+}
+
+/// Paint the pixels covered by the brush centered on a pixel.
+fn paint(state:&mut UiState, x:usize, y:usize) {
+    let (size, round) = state.brush;
+    for (x, y) in raster::brush_cells((x, y), size, round) {
+        paint_pixel(state, x, y);
+    }
+}
+
+/// Paint a pixel with the current color and opacity, along with its reflections across the middle
+/// of the frame being edited when mirroring is enabled. Pixels out of this frame, or out of the
+/// selection if any, are left as is.
+fn paint_pixel(state:&mut UiState, x:usize, y:usize) {
+    let (_, h) = state.canvas.size();
+    let (x0, w) = frame_columns(state);
+    if x < x0 || x >= x0 + w || y >= h {
+        return
+    }
+
+    let (mx, my) = state.mirror;
+    let mirrored_x = x0 + (w - 1 - (x - x0));
+
+    let mut positions = HashSet::new();
+    positions.insert((x, y));
+    if mx {
+        positions.insert((mirrored_x, y));
+    }
+    if my {
+        positions.insert((x, h - 1 - y));
+    }
+    if mx && my {
+        positions.insert((mirrored_x, h - 1 - y));
+    }
+
+    // when there is a selection, only the selected pixels are painted
+    if !state.selection.is_empty() {
+        positions.retain(|pos| state.selection.contains(pos));
+    }
+
+    for (x, y) in positions {
+        state.canvas.blend_pixel(x, y, state.color, state.opacity);
+    }
+}
+
+/// Edit the previous or the next frame, moving the cursor by one frame.
+fn change_frame(ui:&mut Ui<UiState>, state:&mut UiState, next:bool) {
+    let frames = state.frames.max(1);
+    let (_, width) = frame_columns(state);
+    let frame = if next { (state.frame + 1) % frames } else { (state.frame + frames - 1) % frames };
+
+    let (x, y) = ui.cursor();
+    let x = (x + frame * width).saturating_sub(state.frame * width);
+    ui.set_cursor(x.min(state.canvas.size().0 - 1), y);
+    state.frame = frame;
+}
+
+/// Make what depends on the size of the canvas fit in it again after the size changed: the
+/// selected pixels out of it are dropped, saved selections included, the frames cannot get
+/// narrower than a pixel, and the cursor is moved back in.
+fn fit_to_canvas(ui:&mut Ui<UiState>, state:&mut UiState) {
+    let (w, h) = state.canvas.size();
+    state.selection.retain(|&(x, y)| x < w && y < h);
+    for saved in state.saved_selections.values_mut() {
+        saved.retain(|&(x, y)| x < w && y < h);
+    }
+
+    state.frames = state.frames.max(1).min(w.max(1));
+    state.frame = state.frame.min(state.frames - 1);
+    if let Some(playback) = state.playback.as_mut() {
+        playback.frame %= state.frames;
+    }
+
+    state.guides.retain(|guide| match *guide {
+        grid::Guide::Horizontal(y) => y < h,
+        grid::Guide::Vertical(x) => x < w,
+    });
+    sync_snap(ui, state);
+
+    ui.clamp_cursor(w, h);
+    state.must_rebuild_canvas = true;
+}
+
+/// Make the cursor snap to the guides of the canvas being edited if snapping is on.
+fn sync_snap(ui:&mut Ui<UiState>, state:&UiState) {
+    if !state.snap {
+        ui.set_snap(None);
+        return
+    }
+
+    let rows = state.guides.iter().filter_map(|guide| match *guide {
+        grid::Guide::Horizontal(y) => Some(y),
+        _ => None,
+    });
+    let columns = state.guides.iter().filter_map(|guide| match *guide {
+        grid::Guide::Vertical(x) => Some(x),
+        _ => None,
+    });
+    ui.set_snap(Some((columns.collect(), rows.collect(), SNAP_DISTANCE)));
+}
+
+/// Move the cursor to the pixel under the mouse pointer and paint it with the current color in
+/// insertion mode. Nothing is done until the pointer enters another pixel, so that a pixel is
+/// not painted again and again over itself.
+fn follow_pointer(ui:&mut Ui<UiState>, state:&mut UiState) {
+    let (x, y) = match pixel_under_pointer(state) {
+        Some(pixel) if Some(pixel) != state.pointer_pixel => pixel,
+        _ => return,
+    };
+    state.pointer_pixel = Some((x, y));
+
+    ui.set_cursor(x, y);
+    if ui.get_mode() == ui::Mode::Insertion {
+        paint(state, x, y);
+    }
+}
+
+/// Return the text of the status line: the mode, the message or the typed keys, the cursor
+/// position, the canvas size, the color under the cursor, the current color and the zoom.
+fn status_line(ui:&Ui<UiState>, state:&UiState) -> String {
+    let (x, y) = ui.cursor();
+    let (w, h) = state.canvas.size();
+    let under = state.canvas.get_composite_color(x, y);
+
+    // the pixels highlighted in visual mode, else the selection, are counted
+    let ruler = ui.get_mode() == ui::Mode::Visual && state.visual_type == VisualType::Ruler;
+    let selected = if ruler {
+        let (dx, dy, distance) = ruler_measure(ui);
+        format!(" | dx {} dy {} d {:.2}", dx, dy, distance)
+    } else if ui.get_mode() == ui::Mode::Visual || !state.selection.is_empty() {
+        format!(" | SEL {}px", active_selection(ui, state).len())
+    } else {
+        String::new()
+    };
+
+    format!("{:?}:{} | {},{} | {}x{} | {} | pen {}{} | {}%",
+        ui.get_mode(), ui.get_message().unwrap_or(ui.get_buffer()),
+        x, y, w, h, color::to_hex(under), color::to_hex(state.color), selected,
+        (state.zoom * 100.0).round())
+}
+
+/// Give the keys of the palette the colors of the palette of the indexed mode, entry by entry,
+/// after undoing or redoing changed it.
+fn sync_palette(state:&mut UiState) {
+    let colors = match state.canvas.indexed_palette() {
+        Some(colors) => colors.to_vec(),
+        None => return,
+    };
+
+    for (i, color) in colors.into_iter().enumerate() {
+        if let Some((key, _)) = state.palette.entry(i) {
+            state.palette.insert(key, color);
+        }
+    }
+}
+
+/// Make the next or the previous entry of the palette the current color.
+fn cycle_palette(state:&mut UiState, next:bool) {
+    let len = state.palette.len();
+    if len == 0 {
+        return
+    }
+
+    let index = state.palette_index.min(len - 1);
+    state.palette_index = if next { (index + 1) % len } else { (index + len - 1) % len };
+    if let Some((_, color)) = state.palette.entry(state.palette_index) {
+        state.color = color;
+    }
+}
+
+/// Make the previous or the next color of the color history the current color, without editing
+/// the canvas.
+fn step_color_history(ui:&mut Ui<UiState>, state:&mut UiState, back:bool) {
+    match state.color_history.step(back) {
+        Some(c) => {
+            state.color = c;
+            let (i, n) = state.color_history.position();
+            ui.set_message(format!("Color {}/{} {}", i, n, color::to_hex(c)));
+        },
+        None => ui.set_message("No more colors"),
+    }
+}
+
+/// Color of the mode in the status line.
+fn mode_color(mode:ui::Mode) -> [u8; 4] {
+    match mode {
+        ui::Mode::Normal => text::WHITE,
+        ui::Mode::Insertion => [96, 255, 96, 255],
+        ui::Mode::Visual => [255, 160, 32, 255],
+        ui::Mode::Command => [255, 255, 64, 255],
+    }
+}
+
+/// Shorten a status line until it fits in the given width, dropping its last fields first.
+fn truncate_status(text:&text::TextRenderer, fid:text::FontID, mut status:String, width:f32)
+    -> String {
+    while text.text_width(&status, fid) > width {
+        match status.rfind(" | ") {
+            Some(i) => status.truncate(i),
+            None => { status.pop(); },
+        }
+    }
+
+    status
+}
+
+/// Rotate the canvas by the given number of quarter turns as a single modification. The cursor
+/// and the selection follow the pixels they were on, the marks are moved back into the canvas.
+fn rotate_canvas(ui:&mut Ui<UiState>, state:&mut UiState, clockwise:bool, turns:usize) {
+    let rotated = if clockwise { canvas::rotated_cw } else { canvas::rotated_ccw };
+
+    state.canvas.begin_transaction();
+    for _ in 0..turns {
+        let size = state.canvas.size();
+        if clockwise {
+            state.canvas.rotate_cw();
+        } else {
+            state.canvas.rotate_ccw();
         }
+
+        let (x, y) = rotated(size, ui.cursor());
+        ui.set_cursor(x, y);
+        state.selection = state.selection.iter().map(|&pos| rotated(size, pos)).collect();
     }
+    state.canvas.commit_transaction();
+
+    fit_to_canvas(ui, state);
 }
 
 /// Create the main UI object.
 fn create_ui() -> Ui<UiState> {
-    let mut ui = Ui::new(|ui: &mut Ui<UiState>, UiState { selection, canvas, palette, ..}, c| {
-        if let Some(color) = palette.get(&c) {
-            if selection.is_empty() {
-                let (x, y) = ui.cursor();
-                canvas.set_pixel_color(x, y, *color);
-            } else {
-                for &(x, y) in selection.iter() {
-                    canvas.set_pixel_color(x, y, *color);
-                }
+    // A palette key paints with its color, which becomes the current color, and so do the keys 1
+    // to 9 with the recently used colors. The space key paints with the current color. The color
+    // is blended over the pixels with the current opacity.
+    let mut ui = Ui::new(|ui: &mut Ui<UiState>, state: &mut UiState, c| {
+        let recent = match c.key {
+            CharKey::Char(d) => d.to_digit(10)
+                .filter(|&d| d > 0)
+                .and_then(|d| state.recent_colors.get(d as usize - 1).cloned()),
+            _ => None,
+        };
+
+        if let Some(new_color) = state.palette.get(&c) {
+            state.color = *new_color;
+        } else if let Some(new_color) = recent {
+            state.color = new_color;
+        } else if c != CharKeyMod::from("<Space>") {
+            return
+        }
+        palette::push_recent(&mut state.recent_colors, state.color, RECENT_COLORS);
+
+        if state.selection.is_empty() {
+            let (x, y) = ui.cursor();
+            paint(state, x, y);
+        } else {
+            for (x, y) in state.selection.clone() {
+                paint(state, x, y);
             }
         }
     });
 
-    let event_listener = |UiState { must_resize, scale:(x,y), window_size, ..} : &mut UiState, e| {
-        match e {
-            WindowEvent::FramebufferSize(bx, by) => {
-                *x = 1.0 / (bx as f32);
-                *y = 1.0 / (by as f32);
-                *must_resize = true;
-                *window_size = (bx as f32, by as f32);
-            },
-            _ => {},
+    // The mouse moves the cursor when its left button is held down, painting in insertion mode,
+    // pans the view when its middle button is held down, and its wheel zooms around the pixel
+    // under the pointer.
+    let event_listener = |ui:&mut Ui<UiState>, state:&mut UiState, e| {
+        match e {
+            WindowEvent::Size(w, h) => {
+                state.scale = (1.0 / (w as f32), 1.0 / (h as f32));
+                state.window_size = (w as f32, h as f32);
+            },
+            WindowEvent::FramebufferSize(bx, by) => {
+                state.must_resize = true;
+                state.framebuffer_size = (bx as u32, by as u32);
+            },
+            WindowEvent::ContentScale(sx, _) => {
+                state.content_scale = sx;
+            },
+            WindowEvent::CursorPos(x, y) => {
+                let (x, y) = (x as f32, y as f32);
+                state.pointer_delta.0 += x - state.pointer.0;
+                state.pointer_delta.1 += y - state.pointer.1;
+                state.pointer = (x, y);
+                if state.painting {
+                    follow_pointer(ui, state);
+                }
+            },
+            WindowEvent::MouseButton(MouseButton::Button1, Action::Press, _)
+                if minimap_to_canvas(state, state.pointer).is_some() => {
+                // clicking the minimap centers the view on the clicked pixel
+                let (x, y) = minimap_to_canvas(state, state.pointer).unwrap();
+                state.center = (-x, -y);
+            },
+            WindowEvent::MouseButton(MouseButton::Button1, action, _) => {
+                state.painting = action != Action::Release;
+                state.pointer_pixel = None;
+                if state.painting {
+                    follow_pointer(ui, state);
+                }
+            },
+            WindowEvent::MouseButton(MouseButton::Button3, action, _) => {
+                state.panning = action != Action::Release;
+            },
+            WindowEvent::Scroll(_, dy) => {
+                let pointer = screen_to_canvas(state, state.pointer);
+                zoom_around(state, state.zoom + 0.1 * dy as f32, pointer);
+            },
+            _ => {},
+        }
+    };
+
+    ui.set_window_event_listener(Some(event_listener));
+
+    // For each of the H, J, K and L keys, we associate a movement described by a pair of integers.
+    // For each of these pairs, we add an object to the UI event handling system.
+    "hjkl."
+        .chars()
+        .zip([(-1,0),(0,1),(0,-1),(1,0),(0,0)].iter())
+        // h: left, j: down, k: up, l: right.
+        .for_each(|(l, &(x,y))| {
+            ui.add_object(l.to_string().as_ref(), move |ui, UiState { canvas,.. }, positions| {
+                positions.insert(ui.cursor());
+                step_cursor(ui, canvas, x, y);
+                positions.insert(ui.cursor());
+            });
+        });
+
+    // Used alone, the same keys move the grabbed pixels if any, else the cursor.
+    "hjkl"
+        .chars()
+        .zip([(-1,0),(0,1),(0,-1),(1,0)].iter())
+        .for_each(|(l, &(x,y))| {
+            ui.add_verb(l.to_string().as_ref(), false, false, move |ui, state, _| {
+                if state.grab.is_some() {
+                    move_grabbed(ui, state, x, y);
+                } else {
+                    step_cursor(ui, &state.canvas, x, y);
+                }
+            });
+        });
+
+    // Grab the selected pixels of the edited frame, to move them with h, j, k and l until <Esc> is
+    // pressed.
+    ui.add_verb("M", false, false, |_, state, _| {
+        let grabbed = in_frame(state, state.selection.clone());
+        if grabbed.is_empty() || state.grab.is_some() {
+            return
+        }
+
+        let canvas = &state.canvas;
+        let pixels = grabbed
+            .iter()
+            .map(|&(x, y)| ((x, y), canvas.get_pixel(x, y)))
+            .collect();
+        let background = grabbed.iter().map(|&pos| (pos, (0, 0, 0, 0))).collect();
+        state.grab = Some(Grab { pixels, offset: (0, 0), background });
+        state.canvas.begin_transaction();
+    });
+
+    // Move to the start of the current row.
+    ui.add_object("0", |ui, UiState { canvas,.. }, positions| {
+        positions.insert(ui.cursor());
+        let (w, h) = canvas.size();
+        let (x, _) = ui.cursor();
+        ui.wrapping_displace(-(x as isize), 0, w, h);
+        positions.insert(ui.cursor());
+    });
+
+    // Move to the end of the current row.
+    ui.add_object("$", |ui, UiState { canvas,.. }, positions| {
+        positions.insert(ui.cursor());
+        let (_, y) = ui.cursor();
+        ui.set_cursor(canvas.size().0.saturating_sub(1), y);
+        positions.insert(ui.cursor());
+    });
+
+    // Move to the top-left corner of the canvas.
+    ui.add_object("gg", |ui, _, positions| {
+        positions.insert(ui.cursor());
+        ui.set_cursor(0, 0);
+        positions.insert(ui.cursor());
+    });
+
+    // Move to the bottom-right corner of the canvas.
+    ui.add_object("G", |ui, UiState { canvas,.. }, positions| {
+        positions.insert(ui.cursor());
+        let (w, h) = canvas.size();
+        ui.set_cursor(w.saturating_sub(1), h.saturating_sub(1));
+        positions.insert(ui.cursor());
+    });
+
+    // Move to the next or previous change of color along the row (w and b), or along the column
+    // (W and B).
+    "wbWB"
+        .chars()
+        .zip([(1, 0), (-1, 0), (0, 1), (0, -1)].iter())
+        .for_each(|(l, &direction)| {
+            ui.add_object(l.to_string().as_ref(), move |ui, UiState { canvas,.. }, positions| {
+                positions.insert(ui.cursor());
+                jump_to_color_change(ui, canvas, direction);
+                positions.insert(ui.cursor());
+            });
+        });
+
+    // TODO: What does it do?
+    ui.add_verb("s", true, true, |_, state, positions| {
+        let positions = in_frame(state, positions.unwrap().clone());
+        state.canvas.begin_transaction();
+        for &(x, y) in &positions {
+            state.canvas.set_pixel_color_rgb(x, y, (255, 255, 255));
+        }
+        state.canvas.commit_transaction();
+    });
+
+    // Erase the selected pixels, or the pixel under the cursor, in the edited frame.
+    ui.add_verb("x", false, true, |ui, state, _| {
+        let set = in_frame(state, active_selection(ui, state));
+        state.canvas.begin_transaction();
+        for &(x, y) in &set {
+            state.canvas.clear_pixel(x, y);
+        }
+        state.canvas.commit_transaction();
+    });
+
+    // Undo the last modification of the canvas.
+    ui.add_verb("u", false, false, |ui, state, _| {
+        let size = state.canvas.size();
+        state.canvas.undo();
+        sync_palette(state);
+        if state.canvas.size() != size {
+            fit_to_canvas(ui, state);
+        }
+    });
+
+    // Redo the last undone modification of the canvas.
+    ui.add_verb("<C-r>", false, false, |ui, state, _| {
+        let size = state.canvas.size();
+        state.canvas.redo();
+        sync_palette(state);
+        if state.canvas.size() != size {
+            fit_to_canvas(ui, state);
+        }
+    });
+
+    // Pick the color displayed under the cursor as the current color.
+    ui.add_verb("<C-p>", false, false, |ui, UiState { canvas, color, .. }, _| {
+        let (x, y) = ui.cursor();
+        *color = canvas.get_composite_color(x, y);
+    });
+
+    // Make the previous or the next color of the color history the current color.
+    ui.add_verb("<C-o>", false, false, |ui, state, _| step_color_history(ui, state, true));
+    ui.add_verb("<C-i>", false, false, |ui, state, _| step_color_history(ui, state, false));
+
+    // Fill the region around the cursor having the same color with the current color, without
+    // going out of the edited frame.
+    ui.add_verb("f", false, true, |ui, state, _| {
+        let (x, y) = ui.cursor();
+        let columns = frame_columns(state);
+        state.canvas.begin_transaction();
+        state.canvas.flood_fill(x, y, state.color, columns);
+        state.canvas.commit_transaction();
+    });
+
+    // Select the region around the cursor having the same color.
+    ui.add_verb("F", false, false, |ui, UiState { canvas, selection, .. }, _| {
+        let (x, y) = ui.cursor();
+        *selection = canvas.contiguous_region(x, y).into_iter().collect();
+    });
+
+    // Select every pixel of the canvas having the color under the cursor.
+    ui.add_verb("<A-f>", false, false, |ui, UiState { canvas, selection, .. }, _| {
+        let (x, y) = ui.cursor();
+        *selection = canvas.same_color_pixels(x, y).into_iter().collect();
+    });
+
+    // Yank the selected pixels, relatively to the top-left corner of the selection, the cursor
+    // being put back on this corner.
+    ui.add_verb("y", false, false, |ui, state, _| {
+        let set = active_selection(ui, state);
+        let ((x0, y0), _) = sel::bounding_box(&set).unwrap_or(((0, 0), (0, 0)));
+
+        let canvas = &state.canvas;
+        state.clipboard = set
+            .iter()
+            .map(|&(x, y)| ((x - x0, y - y0), canvas.get_pixel(x, y)))
+            .collect();
+
+        if ui.get_mode() == ui::Mode::Visual {
+            ui.set_mode(ui::Mode::Normal);
+        }
+        ui.set_cursor(x0, y0);
+    });
+
+    // Paste the yanked pixels, the top-left corner of the yanked selection being on the cursor.
+    // The pixels out of the edited frame are dropped.
+    ui.add_verb("p", false, true, |ui, state, _| {
+        let (cx, cy) = ui.cursor();
+        let (_, h) = state.canvas.size();
+        let (x0, w) = frame_columns(state);
+
+        state.canvas.begin_transaction();
+        for &((dx, dy), pixel) in state.clipboard.iter() {
+            let (x, y) = (cx + dx, cy + dy);
+            if x >= x0 && x < x0 + w && y < h {
+                state.canvas.set_pixel(x, y, pixel);
+            }
+        }
+        state.canvas.commit_transaction();
+    });
+
+    // Zoom in the canvas, the pixel under the cursor staying in place. In visual mode, add the
+    // highlighted pixels to the selection instead.
+    ui.add_verb("<S-+>", false, false, |ui, state, _| {
+        if ui.get_mode() == ui::Mode::Visual {
+            combine_selection(ui, state, SelectionOp::Union);
+            return
+        }
+
+        let (x, y) = ui.cursor();
+        zoom_around(state, state.zoom + 0.1, (x as f32 + 0.5, y as f32 + 0.5));
+    });
+
+    // Zoom out the canvas, the pixel under the cursor staying in place. In visual mode, remove the
+    // highlighted pixels from the selection instead.
+    ui.add_verb("-", false, false, |ui, state, _| {
+        if ui.get_mode() == ui::Mode::Visual {
+            combine_selection(ui, state, SelectionOp::Difference);
+            return
+        }
+
+        let (x, y) = ui.cursor();
+        zoom_around(state, state.zoom - 0.1, (x as f32 + 0.5, y as f32 + 0.5));
+    });
+
+    // In visual mode, keep only the pixels of the selection that are highlighted.
+    ui.add_verb("&", false, false, |ui, state, _| {
+        if ui.get_mode() == ui::Mode::Visual {
+            combine_selection(ui, state, SelectionOp::Intersection);
+        }
+    });
+
+    // Edit the previous or the next frame.
+    ui.add_verb("[", false, false, |ui, state, _| change_frame(ui, state, false));
+    ui.add_verb("]", false, false, |ui, state, _| change_frame(ui, state, true));
+
+    // Make the next or the previous color of the palette the current color.
+    ui.add_verb("<Tab>", false, false, |_, state, _| cycle_palette(state, true));
+    ui.add_verb("<S-Tab>", false, false, |_, state, _| cycle_palette(state, false));
+
+    // Enter command mode.
+    ui.add_verb(":", false, false, |ui, _, _| {
+        ui.set_mode(ui::Mode::Command);
+    });
+
+    // Enter insert mode. If the current mode was visual, select the pixels that were highlighted.
+    // Everything painted until the mode is left is undone at once.
+    ui.add_verb("i", false, false, |ui, UiState { selection, visual_type, canvas, .. }, _| {
+        if ui.get_mode() == ui::Mode::Visual {
+            selection.clear();
+            visual_type.select_pixels(&mut **selection, ui, canvas.size());
+        }
+        canvas.begin_transaction();
+        ui.set_mode(ui::Mode::Insertion);
+    });
+
+    // Enter square visual mode.
+    ui.add_verb("v", false, false, |ui, UiState { visual_type, .. }, _| {
+        *visual_type = VisualType::Square;
+        ui.set_mode(ui::Mode::Visual);
+    });
+
+    // Enter square outline visual mode.
+    ui.add_verb("r", false, false, |ui, UiState { visual_type, .. }, _| {
+        *visual_type = VisualType::SquareOutline;
+        ui.set_mode(ui::Mode::Visual);
+    });
+
+    // Enter circle visual mode.
+    ui.add_verb("V", false, false, |ui, UiState { visual_type, .. }, _| {
+        *visual_type = VisualType::Circle;
+        ui.set_mode(ui::Mode::Visual);
+    });
+
+    // Enter line visual mode, from the position where the mode is entered to the cursor.
+    ui.add_verb("<C-v>", false, false, |ui, UiState { visual_type, .. }, _| {
+        *visual_type = VisualType::Line;
+        ui.set_mode(ui::Mode::Visual);
+    });
+
+    // Enter ruler visual mode, measuring the distance from the position where the mode is entered
+    // to the cursor without highlighting any pixel to edit.
+    ui.add_verb("D", false, false, |ui, UiState { visual_type, .. }, _| {
+        *visual_type = VisualType::Ruler;
+        ui.set_mode(ui::Mode::Visual);
+    });
+
+    // Enter row visual mode, highlighting whole rows from the position where the mode is entered
+    // to the cursor.
+    ui.add_verb("R", false, false, |ui, UiState { visual_type, .. }, _| {
+        *visual_type = VisualType::Rows;
+        ui.set_mode(ui::Mode::Visual);
+    });
+
+    // Enter column visual mode, highlighting whole columns.
+    ui.add_verb("C", false, false, |ui, UiState { visual_type, .. }, _| {
+        *visual_type = VisualType::Columns;
+        ui.set_mode(ui::Mode::Visual);
+    });
+
+    // TODO: What does it do?
+    ui.add_verb("H", false, false, |_, UiState { center,.. }:&mut UiState, _| {
+        center.0 -= 1.0;
+    });
+    ui.add_verb("J", false, false, |_, UiState { center,.. }:&mut UiState, _| {
+        center.1 += 1.0;
+    });
+    ui.add_verb("K", false, false, |_, UiState { center,.. }:&mut UiState, _| {
+        center.1 -= 1.0;
+    });
+    ui.add_verb("L", false, false, |_, UiState { center,.. }:&mut UiState, _| {
+        center.0 += 1.0;
+    });
+
+    // Add the quit commands
+    ui.add_command("q", |ui, _, _| {
+        ui.close();
+        Ok(())
+    });
+
+    ui.add_command("quit", |ui, _, _| {
+        ui.close();
+        Ok(())
+    });
+
+    // Empty action.
+    ui.add_verb("_", true, false, |_,_,_| {});
+
+    // Add default key bindings for arrows in insert mode.
+    ui.bind_key("<Left>", ui::Mode::Insertion, "<Esc>hi");
+    ui.bind_key("<Right>", ui::Mode::Insertion, "<Esc>li");
+    ui.bind_key("<Down>", ui::Mode::Insertion, "<Esc>ji");
+    ui.bind_key("<Up>", ui::Mode::Insertion, "<Esc>ki");
+
+    // Write the canvas into a PNG file. Without argument, the last file written is used again.
+    ui.add_command("w", |ui, state, args| write_canvas(ui, state, args.first().copied()));
+
+    // Write the canvas, then quit.
+    ui.add_command("wq", |ui, state, args| {
+        write_canvas(ui, state, args.first().copied())?;
+        ui.close();
+        Ok(())
+    });
+
+    // Load the swap file left by a previous session, found when starting, then delete it. The
+    // file the swap file belongs to is edited again, and the recovery can be undone.
+    ui.add_command("recover", |ui, state, _| {
+        let (path, file) = state.swap_to_recover.take()
+            .ok_or_else(|| "No swap file".to_string())?;
+        let bytes = fs::read(&path).map_err(|e| format!("Cannot open \"{}\": {}", path, e))?;
+        let (canvas, project) = project::read(&bytes[..])
+            .map_err(|e| format!("Cannot open \"{}\": {}", path, e))?;
+
+        state.canvas.begin_transaction();
+        state.canvas.replace_with(canvas);
+        state.canvas.commit_transaction();
+        state.frames = project.frames;
+        state.selection = project.selection.into();
+        fit_to_canvas(ui, state);
+        state.file = file;
+
+        ui.set_message(format!("\"{}\" recovered", path));
+        let _ = fs::remove_file(&path);
+        Ok(())
+    });
+
+    // Write the rectangle containing the selection into a PNG file, the pixels out of the selection
+    // being transparent. Without selection, the whole canvas is written.
+    ui.add_command("wsel", |ui, UiState { canvas, selection, .. }, args| {
+        let path = match args.first() {
+            Some(path) => path,
+            None => return Err("No file name".to_string()),
+        };
+
+        let (img, written) = match sel::bounding_box(selection) {
+            Some(((x, y), (w, h))) => {
+                let mut img = canvas.new_from_region(x, y, w, h).to_rgba_image();
+                for (i, j, pixel) in img.enumerate_pixels_mut() {
+                    if !selection.contains(&(x + i as usize, y + j as usize)) {
+                        *pixel = image::Rgba([0, 0, 0, 0]);
+                    }
+                }
+                (img, "selection")
+            },
+            None => (canvas.to_rgba_image(), "whole canvas, no selection"),
+        };
+
+        match img.save_with_format(path, ImageFormat::Png) {
+            Ok(()) => ui.set_message(format!("\"{}\" written ({})", path, written)),
+            Err(e) => return Err(format!("Cannot write \"{}\": {}", path, e)),
+        }
+        Ok(())
+    });
+
+    // Split the canvas into the given number of frames, from left to right.
+    ui.add_command("frames", |_, UiState { canvas, frames, frame, playback, .. }, args| {
+        match args.first().and_then(|n| n.parse::<usize>().ok()) {
+            Some(n) if n > 0 && n <= canvas.size().0 => {
+                *frames = n;
+                *frame = (*frame).min(n - 1);
+                if let Some(playback) = playback.as_mut() {
+                    playback.frame %= n;
+                }
+            },
+            _ => return Err("Usage: frames <n>".to_string()),
+        }
+        Ok(())
+    });
+
+    // Play the frames of the canvas in a corner, each one being shown the given number of
+    // milliseconds.
+    ui.add_command("play", |_, UiState { playback, .. }, args| {
+        match args.first().and_then(|ms| ms.parse::<u64>().ok()) {
+            Some(ms) if ms > 0 => *playback = Some(Playback {
+                interval: Duration::from_millis(ms),
+                elapsed: Duration::from_millis(0),
+                frame: 0,
+            }),
+            _ => return Err("Usage: play <ms>".to_string()),
+        }
+        Ok(())
+    });
+
+    // Show or hide the previous and next frames over the frame being edited.
+    ui.add_command("onion", |_, UiState { onion, .. }, _| {
+        *onion = !*onion;
+        Ok(())
+    });
+
+    // Stop playing the frames.
+    ui.add_command("stop", |_, UiState { playback, .. }, _| {
+        *playback = None;
+        Ok(())
+    });
+
+    // Write the canvas as source code defining its RGBA bytes, such as `:export rust sprite.rs`.
+    ui.add_command("export", |ui, UiState { canvas, .. }, args| {
+        let (code, path) = match args.as_slice() {
+            ["rust", path] => (codegen::to_rust_array(canvas, "SPRITE"), path),
+            ["c", path] => (codegen::to_c_array(canvas, "SPRITE"), path),
+            _ => return Err("Usage: export rust|c <path>".to_string()),
+        };
+
+        match fs::write(path, code) {
+            Ok(()) => ui.set_message(format!("\"{}\" written", path)),
+            Err(e) => return Err(format!("Cannot write \"{}\": {}", path, e)),
+        }
+        Ok(())
+    });
+
+    // Open a blank canvas in a new buffer.
+    ui.add_command("enew", |ui, state, _| {
+        state.buffers.push(Buffer::new());
+        switch_buffer(ui, state, state.buffers.len() - 1);
+        Ok(())
+    });
+
+    // Edit the next, the previous or the given buffer, counting from 1, such as `:b 2`.
+    ui.add_command("bn", |ui, state, _| {
+        switch_buffer(ui, state, (state.buffer + 1) % state.buffers.len());
+        Ok(())
+    });
+
+    ui.add_command("bp", |ui, state, _| {
+        let count = state.buffers.len();
+        switch_buffer(ui, state, (state.buffer + count - 1) % count);
+        Ok(())
+    });
+
+    ui.add_command("b", |ui, state, args| {
+        match args.first().and_then(|n| n.parse::<usize>().ok()) {
+            Some(n) if n >= 1 && n <= state.buffers.len() => switch_buffer(ui, state, n - 1),
+            _ => return Err(format!("Usage: b <1-{}>", state.buffers.len())),
+        }
+        Ok(())
+    });
+
+    // Close the buffer being edited and edit the previous one. The last buffer cannot be closed.
+    ui.add_command("bd", |ui, state, _| {
+        if state.buffers.len() == 1 {
+            return Err("Cannot close the last buffer".to_string())
+        }
+
+        let closed = state.buffer;
+        let next = if closed == 0 { 1 } else { closed - 1 };
+        switch_buffer(ui, state, next);
+        state.buffers.remove(closed);
+        if state.buffer > closed {
+            state.buffer -= 1;
+        }
+        if let Some(split) = state.split.as_mut() {
+            if split.buffer == closed {
+                split.buffer = state.buffer;
+            } else if split.buffer > closed {
+                split.buffer -= 1;
+            }
+        }
+        buffer_message(ui, state);
+        Ok(())
+    });
+
+    // Split the window vertically, the new pane showing the given buffer, counting from 1, or the
+    // edited one. The focus stays on the edited buffer, in the left pane.
+    ui.add_command("vsplit", |_, state, args| {
+        let buffer = match args.first().map(|n| n.parse::<usize>()) {
+            None => state.buffer,
+            Some(Ok(n)) if n >= 1 && n <= state.buffers.len() => n - 1,
+            _ => return Err(format!("Usage: vsplit [1-{}]", state.buffers.len())),
+        };
+
+        let (zoom, center) = (state.zoom, state.center);
+        state.split = Some(Split { buffer, focus_left: true, zoom, center });
+        Ok(())
+    });
+
+    // Show the focused pane alone.
+    ui.add_command("only", |_, state, _| {
+        state.split = None;
+        Ok(())
+    });
+
+    // `:wincmd w` moves the focus to the other pane, editing the buffer it shows.
+    ui.add_command("wincmd", |ui, state, args| {
+        let split = match (state.split, args.as_slice()) {
+            (Some(split), ["w"]) => split,
+            (None, ["w"]) => return Ok(()),
+            _ => return Err("Usage: wincmd w".to_string()),
+        };
+
+        let buffer = state.buffer;
+        switch_buffer(ui, state, split.buffer);
+        let zoom = std::mem::replace(&mut state.zoom, split.zoom);
+        let center = std::mem::replace(&mut state.center, split.center);
+        state.split = Some(Split { buffer, focus_left: !split.focus_left, zoom, center });
+        Ok(())
+    });
+    ui.bind_key("<C-w>w", ui::Mode::Normal, ":wincmd w<CR>");
+
+    // List the buffers.
+    ui.add_command("ls", |ui, state, _| {
+        let names : Vec<String> = (0..state.buffers.len())
+            .map(|i| {
+                let file = if i == state.buffer { &state.file } else { &state.buffers[i].file };
+                let name = file.as_deref().unwrap_or("[No Name]");
+                let current = if i == state.buffer { "%" } else { "" };
+                format!("{}{} \"{}\"", i + 1, current, name)
+            })
+            .collect();
+        ui.set_message(names.join(" | "));
+        Ok(())
+    });
+
+    // Edit an image file: the canvas is replaced by the content of the image.
+    ui.add_command("e", |ui, state, args| {
+        let path = match args.first() {
+            Some(path) => path.to_string(),
+            None => return Err("No file name".to_string()),
+        };
+
+        match open(&path) {
+            Ok(img) => {
+                state.canvas = Canvas::from_rgba_image(&img.to_rgba());
+                state.selection.clear();
+                fit_to_canvas(ui, state);
+
+                let (w, h) = state.canvas.size();
+                ui.set_message(format!("\"{}\" {}x{}", path, w, h));
+                state.file = Some(path);
+            },
+            Err(e) => return Err(format!("Cannot open \"{}\": {}", path, e)),
+        }
+        Ok(())
+    });
+
+    // Write the canvas with its layers, along with the frames, the palette, the current color and
+    // the selection, into a project file, such as `:wproject sprite.vip`.
+    ui.add_command("wproject", |ui, state, args| {
+        let path = match args.first() {
+            Some(path) => path.to_string(),
+            None => return Err("No file name".to_string()),
+        };
+        write_project(state, &path)?;
+        ui.set_message(format!("\"{}\" written", path));
+        Ok(())
+    });
+
+    // Edit a project file written by `:wproject`, replacing the canvas, the palette and the rest.
+    // The saved selections and the guides of the previous canvas are dropped.
+    ui.add_command("eproject", |ui, state, args| {
+        let path = match args.first() {
+            Some(path) => path.to_string(),
+            None => return Err("No file name".to_string()),
+        };
+        let bytes = fs::read(&path).map_err(|e| format!("Cannot open \"{}\": {}", path, e))?;
+        let (canvas, project) = project::read(&bytes[..])
+            .map_err(|e| format!("Cannot open \"{}\": {}", path, e))?;
+
+        state.canvas = canvas;
+        state.frames = project.frames;
+        state.frame = 0;
+        state.playback = None;
+        state.palette.clear();
+        for (key, color) in project.palette {
+            state.palette.insert(key, color);
+        }
+        state.palette_index = 0;
+        state.color = project.color;
+        state.selection = project.selection.into();
+        state.saved_selections.clear();
+        state.guides.clear();
+        fit_to_canvas(ui, state);
+
+        // the project is no image, `:w` needs to be given the file to write the canvas to
+        state.file = None;
+
+        let (w, h) = state.canvas.size();
+        ui.set_message(format!("\"{}\" {}x{}", path, w, h));
+        Ok(())
+    });
+
+    // Change the size of the canvas, keeping the pixels that still fit in.
+    ui.add_command("resize", |ui, state, args| {
+        let w = args.first().and_then(|w| w.parse().ok());
+        let h = args.get(1).and_then(|h| h.parse().ok());
+        match (w, h) {
+            (Some(w), Some(h)) if w > 0 && h > 0 => {
+                state.canvas.begin_transaction();
+                state.canvas.resize(w, h);
+                state.canvas.commit_transaction();
+                fit_to_canvas(ui, state);
+            },
+            _ => return Err("Usage: resize <width> <height>".to_string()),
+        }
+        Ok(())
+    });
+
+    // Crop the canvas to the rectangle containing the selection.
+    ui.add_command("crop", |ui, state, _| {
+        match sel::bounding_box(&state.selection) {
+            Some(((x, y), (w, h))) => {
+                state.canvas.begin_transaction();
+                state.canvas.crop(x, y, w, h);
+                state.canvas.commit_transaction();
+
+                state.selection.clear();
+                ui.set_cursor(0, 0);
+                fit_to_canvas(ui, state);
+            },
+            None => return Err("No selection".to_string()),
+        }
+        Ok(())
+    });
+
+    // Grow or shrink the selection by a number of pixels, such as `:selgrow 2`, going around its
+    // corners too with `:selgrow 2 diag`.
+    for &(name, grow) in [("selgrow", true), ("selshrink", false)].iter() {
+        ui.add_command(name, move |_, UiState { canvas, selection, .. }, args| {
+            let n = args.first().and_then(|n| n.parse().ok());
+            let diagonal = match args.get(1) {
+                None => Some(false),
+                Some(&"diag") => Some(true),
+                Some(_) => None,
+            };
+
+            let size = canvas.size();
+            match (n, diagonal) {
+                (Some(n), Some(diagonal)) if grow => sel::grow(selection, size, n, diagonal),
+                (Some(n), Some(diagonal)) => sel::shrink(selection, size, n, diagonal),
+                _ => return Err(format!("Usage: {} <n> [diag]", name)),
+            }
+            Ok(())
+        });
+    }
+
+    // Select every pixel of the canvas.
+    ui.add_command("selall", |_, UiState { canvas, selection, .. }, _| {
+        *selection = canvas.all_pixels().into_iter().collect();
+        Ok(())
+    });
+
+    // Drop the selection.
+    ui.add_command("selnone", |_, UiState { selection, .. }, _| {
+        selection.clear();
+        Ok(())
+    });
+
+    // Select the pixels of the canvas which are not selected, and only them.
+    ui.add_command("selinvert", |_, UiState { canvas, selection, .. }, _| {
+        *selection = canvas
+            .all_pixels()
+            .into_iter()
+            .filter(|pos| !selection.contains(pos))
+            .collect();
+        Ok(())
+    });
+
+    // Save the selection under a name.
+    ui.add_command("selsave", |_, UiState { selection, saved_selections, .. }, args| {
+        match args.first() {
+            Some(name) => { saved_selections.insert(name.to_string(), selection.clone()); },
+            None => return Err("Usage: selsave <name>".to_string()),
+        }
+        Ok(())
+    });
+
+    // Replace the selection with a saved one. Pixels out of the canvas are dropped.
+    ui.add_command("selload", |_, UiState { canvas, selection, saved_selections, .. }, args| {
+        match args.first().and_then(|name| saved_selections.get(*name)) {
+            Some(saved) => {
+                let (w, h) = canvas.size();
+                *selection = saved.iter().cloned().filter(|&(x, y)| x < w && y < h).collect();
+            },
+            None => return Err("Usage: selload <name>".to_string()),
+        }
+        Ok(())
+    });
+
+    // List the names of the saved selections.
+    ui.add_command("sellist", |ui, UiState { saved_selections, .. }, _| {
+        let mut names : Vec<&String> = saved_selections.keys().collect();
+        names.sort();
+        if names.is_empty() {
+            ui.set_message("No saved selection");
+        } else {
+            ui.set_message(names.iter().map(|name| name.as_str()).collect::<Vec<_>>().join(" "));
+        }
+        Ok(())
+    });
+
+    // Roll the canvas by the given offsets, such as `:shift 8 0`, what goes out on one side coming
+    // back on the other one, to check that it tiles seamlessly.
+    ui.add_command("shift", |_, UiState { canvas, .. }, args| {
+        let offsets : Option<Vec<isize>> = args.iter().map(|a| a.parse().ok()).collect();
+        match offsets.as_deref() {
+            Some(&[dx, dy]) => {
+                canvas.begin_transaction();
+                canvas.shift(dx, dy);
+                canvas.commit_transaction();
+            },
+            _ => return Err("Usage: shift <dx> <dy>".to_string()),
+        }
+        Ok(())
+    });
+
+    // Mirror the rectangle containing the selection, or the whole canvas, left to right. Only the
+    // edited frame is mirrored when the canvas is split into frames.
+    ui.add_command("fliph", |_, state, _| {
+        let region = filter_region(state);
+        state.canvas.begin_transaction();
+        match region.as_ref().map(sel::bounding_box) {
+            Some(Some(((x, y), (w, h)))) => state.canvas.flip_region_horizontal(x, y, w, h),
+            Some(None) => {},
+            None => state.canvas.flip_horizontal(),
+        }
+        state.canvas.commit_transaction();
+        Ok(())
+    });
+
+    // Mirror the rectangle containing the selection, or the whole canvas, top to bottom. Only the
+    // edited frame is mirrored when the canvas is split into frames.
+    ui.add_command("flipv", |_, state, _| {
+        let region = filter_region(state);
+        state.canvas.begin_transaction();
+        match region.as_ref().map(sel::bounding_box) {
+            Some(Some(((x, y), (w, h)))) => state.canvas.flip_region_vertical(x, y, w, h),
+            Some(None) => {},
+            None => state.canvas.flip_vertical(),
+        }
+        state.canvas.commit_transaction();
+        Ok(())
+    });
+
+    // Rotate the canvas.
+    ui.add_command("rot90", |ui, state, _| {
+        rotate_canvas(ui, state, true, 1);
+        Ok(())
+    });
+    ui.add_command("rot180", |ui, state, _| {
+        rotate_canvas(ui, state, true, 2);
+        Ok(())
+    });
+    ui.add_command("rot270", |ui, state, _| {
+        rotate_canvas(ui, state, false, 1);
+        Ok(())
+    });
+
+    // Manage the layers of the canvas: add a new one, move to the next or previous one, show or
+    // hide the active one, or change its opacity.
+    ui.add_command("layer", |ui, UiState { canvas, .. }, args| {
+        let active = canvas.active_layer();
+        match (args.first().copied(), args.get(1).and_then(|o| o.parse().ok())) {
+            (Some("new"), _) => {
+                canvas.begin_transaction();
+                canvas.add_layer();
+                canvas.commit_transaction();
+            },
+            (Some("next"), _) => canvas.set_active_layer(active + 1),
+            (Some("prev"), _) => canvas.set_active_layer(active.saturating_sub(1)),
+            (Some("toggle"), _) => {
+                canvas.begin_transaction();
+                canvas.toggle_layer_visibility();
+                canvas.commit_transaction();
+            },
+            (Some("opacity"), Some(opacity)) => {
+                canvas.begin_transaction();
+                canvas.set_layer_opacity(opacity);
+                canvas.commit_transaction();
+            },
+            _ => return Err("Usage: layer new|next|prev|toggle|opacity <f>".to_string()),
+        }
+
+        let layer = &canvas.layers()[canvas.active_layer()];
+        ui.set_message(format!("{} ({}/{}){}", layer.name, canvas.active_layer() + 1,
+                               canvas.layers().len(), if layer.visible { "" } else { " hidden" }));
+        Ok(())
+    });
+
+    // Load a font file and use it for the status line.
+    ui.add_command("font", |_, UiState { font_to_load, .. }, args| {
+        match args.first() {
+            Some(path) => *font_to_load = Some(path.to_string()),
+            None => return Err("No file name".to_string()),
+        }
+        Ok(())
+    });
+
+    // Draw a text on the canvas with the current color, its top-left corner being on the cursor.
+    ui.add_command("text", |_, UiState { text_to_stamp, .. }, args| {
+        if args.is_empty() {
+            return Err("Usage: text <string>".to_string())
+        }
+
+        *text_to_stamp = Some(args.join(" "));
+        Ok(())
+    });
+
+    // Show or hide the grid over the canvas.
+    ui.add_command("grid", |_, UiState { grid, .. }, _| {
+        *grid = !*grid;
+        Ok(())
+    });
+
+    // Add a horizontal guide along a row, or a vertical one along a column, such as `:guide v 8`.
+    ui.add_command("guide", |ui, state, args| {
+        let (w, h) = state.canvas.size();
+        match args.as_slice() {
+            ["h", y] => match y.parse::<usize>() {
+                Ok(y) if y < h => state.guides.push(grid::Guide::Horizontal(y)),
+                _ => return Err("The guide is out of the canvas".to_string()),
+            },
+            ["v", x] => match x.parse::<usize>() {
+                Ok(x) if x < w => state.guides.push(grid::Guide::Vertical(x)),
+                _ => return Err("The guide is out of the canvas".to_string()),
+            },
+            _ => return Err("Usage: guide h|v <n>".to_string()),
+        }
+        sync_snap(ui, state);
+        Ok(())
+    });
+
+    // Remove all the guides.
+    ui.add_command("guideclear", |ui, state, _| {
+        state.guides.clear();
+        sync_snap(ui, state);
+        Ok(())
+    });
+
+    // Show or hide the minimap.
+    ui.add_command("minimap", |_, UiState { minimap, .. }, _| {
+        *minimap = !*minimap;
+        Ok(())
+    });
+
+    // Show or hide copies of the canvas around it, three by three.
+    ui.add_command("tile", |_, UiState { tile, .. }, _| {
+        *tile = !*tile;
+        Ok(())
+    });
+
+    // Show or hide the checkerboard behind the canvas. With arguments, set the size of the checks
+    // on screen and their grey levels (from 0 to 255), such as `:checker 8 204 153`.
+    ui.add_command("checker", |_, UiState { checker, .. }, args| {
+        if args.is_empty() {
+            checker.enabled = !checker.enabled;
+            return Ok(())
+        }
+
+        let values : Option<Vec<f32>> = args.iter().map(|a| a.parse().ok()).collect();
+        match values.as_deref() {
+            Some([size]) => *checker = background::Checker { enabled: true, size: *size, ..*checker },
+            Some([size, light, dark]) => *checker = background::Checker {
+                enabled: true,
+                size: *size,
+                light: light / 255.0,
+                dark: dark / 255.0,
+            },
+            _ => return Err("Usage: checker [size [light dark]]".to_string()),
+        }
+        Ok(())
+    });
+
+    // Set the opacity the current color is painted with, between 0 and 1.
+    ui.add_command("opacity", |_, UiState { opacity, .. }, args| {
+        match args.first().and_then(|o| o.parse::<f32>().ok()) {
+            Some(o) => *opacity = o.max(0.0).min(1.0),
+            None => return Err("Usage: opacity <f>".to_string()),
+        }
+        Ok(())
+    });
+
+    // Set the size of the brush, square unless `round` is given, such as `:brush 3 round`.
+    ui.add_command("brush", |_, UiState { brush, .. }, args| {
+        match (args.first().and_then(|n| n.parse::<usize>().ok()), args.get(1).copied()) {
+            (Some(size), None) if size > 0 => *brush = (size, false),
+            (Some(size), Some("round")) if size > 0 => *brush = (size, true),
+            _ => return Err("Usage: brush <n> [round]".to_string()),
+        }
+        Ok(())
+    });
+
+    // Mirror painting left to right (x), top to bottom (y), both ways (xy) or not at all (off).
+    ui.add_command("mirror", |_, UiState { mirror, .. }, args| {
+        match args.first().copied() {
+            Some("x") => *mirror = (true, false),
+            Some("y") => *mirror = (false, true),
+            Some("xy") => *mirror = (true, true),
+            Some("off") => *mirror = (false, false),
+            _ => return Err("Usage: mirror x|y|xy|off".to_string()),
+        }
+        Ok(())
+    });
+
+    // Load a GIMP palette, its colors being bound to the keys of the keyboard in order. In indexed
+    // mode, the canvas is restricted to the new palette.
+    ui.add_command("palette", |ui, UiState { palette, canvas, .. }, args| {
+        let path = match args.first() {
+            Some(path) => path,
+            None => return Err("No file name".to_string()),
+        };
+
+        match palette::load_gpl(path) {
+            Ok(colors) => {
+                // the colors beyond the keys to bind them to are left out, which is reported
+                let dropped = colors.len().saturating_sub(palette::PALETTE_KEYS.len());
+                palette.clear();
+                for (&key, (r, g, b, _)) in palette::PALETTE_KEYS.iter().zip(colors.into_iter()) {
+                    palette.insert(CharKeyMod::from(key), (r, g, b));
+                }
+                if dropped > 0 {
+                    ui.set_message(format!("{} colors loaded, {} dropped", palette.len(), dropped));
+                }
+
+                if canvas.is_indexed() && !palette.is_empty() {
+                    canvas.begin_transaction();
+                    canvas.set_indexed(Some(palette.colors()));
+                    canvas.commit_transaction();
+                }
+            },
+            Err(e) => return Err(format!("Cannot load \"{}\": {}", path, e)),
+        }
+        Ok(())
+    });
+
+    // Replace a color with another one on the whole canvas, such as `:replace #000000 #ff8800`.
+    // The colors whose channels are within the optional tolerance are replaced too.
+    ui.add_command("replace", |ui, UiState { canvas, .. }, args| {
+        let from = args.first().and_then(|hex| color::parse_hex(hex));
+        let to = args.get(1).and_then(|hex| color::parse_hex(hex));
+        let tolerance = match args.get(2) {
+            Some(t) => t.parse().ok(),
+            None => Some(0),
+        };
+
+        match (from, to, tolerance) {
+            (Some(from), Some(to), Some(tolerance)) => {
+                canvas.begin_transaction();
+                let changed = canvas.replace_color_within(from, to, tolerance);
+                canvas.commit_transaction();
+                ui.set_message(format!("{} pixels replaced", changed.len()));
+            },
+            _ => return Err("Usage: replace #RRGGBB #RRGGBB [tolerance]".to_string()),
+        }
+        Ok(())
+    });
+
+    // Invert the colors of the selection, or of the whole canvas.
+    ui.add_command("invert", |_, state, _| {
+        let region = filter_region(state);
+        state.canvas.begin_transaction();
+        state.canvas.invert(region.as_ref());
+        state.canvas.commit_transaction();
+        Ok(())
+    });
+
+    // Give a color to the selection, or to the whole canvas, such as `:fill #000000`.
+    ui.add_command("fill", |_, state, args| {
+        match args.first().and_then(|hex| color::parse_hex(hex)) {
+            Some(color) => {
+                let region = filter_region(state);
+                state.canvas.begin_transaction();
+                state.canvas.fill(color, region.as_ref());
+                state.canvas.commit_transaction();
+            },
+            None => return Err("Usage: fill #RRGGBB".to_string()),
+        }
+        Ok(())
+    });
+
+    // Add random noise to the selection, or to the whole canvas, such as `:noise 8`.
+    ui.add_command("noise", |_, state, args| {
+        match args.first().map(|amount| amount.parse()) {
+            Some(Ok(amount)) => {
+                let region = filter_region(state);
+                state.canvas.begin_transaction();
+                state.canvas.noise(region.as_ref(), amount, &mut state.rng);
+                state.canvas.commit_transaction();
+            },
+            _ => return Err("Usage: noise <amount>".to_string()),
+        }
+        Ok(())
+    });
+
+    // Replace the selection, or the whole canvas, by an ordered dither of two colors following its
+    // lightness, such as `:dither #000000 #ffffff`.
+    ui.add_command("dither", |_, state, args| {
+        let dark = args.first().and_then(|hex| color::parse_hex(hex));
+        let light = args.get(1).and_then(|hex| color::parse_hex(hex));
+        match (dark, light) {
+            (Some(dark), Some(light)) => {
+                let region = filter_region(state);
+                state.canvas.begin_transaction();
+                state.canvas.dither(region.as_ref(), dark, light);
+                state.canvas.commit_transaction();
+            },
+            _ => return Err("Usage: dither #RRGGBB #RRGGBB".to_string()),
+        }
+        Ok(())
+    });
+
+    // Turn the selection, or the whole canvas, into shades of grey.
+    ui.add_command("grayscale", |_, state, _| {
+        let region = filter_region(state);
+        state.canvas.begin_transaction();
+        state.canvas.grayscale(region.as_ref());
+        state.canvas.commit_transaction();
+        Ok(())
+    });
+
+    // Shift the hue, the saturation and the value of the selection, or of the whole canvas, such
+    // as `:hsv 180 0 -0.2`. The hue is in degrees, the saturation and the value between 0 and 1.
+    ui.add_command("hsv", |_, state, args| {
+        let deltas : Option<Vec<f32>> = args.iter().map(|a| a.parse().ok()).collect();
+        match deltas.as_deref() {
+            Some(&[dh, ds, dv]) => {
+                let region = filter_region(state);
+                state.canvas.begin_transaction();
+                state.canvas.adjust_hsv(region.as_ref(), (dh, ds, dv));
+                state.canvas.commit_transaction();
+            },
+            _ => return Err("Usage: hsv <dh> <ds> <dv>".to_string()),
+        }
+        Ok(())
+    });
+
+    // Paint a color around the selection, such as `:outline #000000`, on the pixels next to its
+    // sides, or also next to its corners with `:outline #000000 diag`.
+    ui.add_command("outline", |_, UiState { canvas, selection, .. }, args| {
+        let color = args.first().and_then(|hex| color::parse_hex(hex));
+        let diagonal = match args.get(1) {
+            None => Some(false),
+            Some(&"diag") => Some(true),
+            Some(_) => None,
+        };
+
+        match (color, diagonal) {
+            (Some(color), Some(diagonal)) => {
+                let (w, h) = canvas.size();
+                canvas.begin_transaction();
+                for (x, y) in sel::outline_of(selection, w, h, diagonal) {
+                    canvas.set_pixel_color_rgb(x, y, color);
+                }
+                canvas.commit_transaction();
+            },
+            _ => return Err("Usage: outline #RRGGBB [diag]".to_string()),
+        }
+        Ok(())
+    });
+
+    // Lighten or darken the selection, or the whole canvas, such as `:bright -16`.
+    ui.add_command("bright", |_, state, args| {
+        match args.first().map(|delta| delta.parse()) {
+            Some(Ok(delta)) => {
+                let region = filter_region(state);
+                state.canvas.begin_transaction();
+                state.canvas.brightness(region.as_ref(), delta);
+                state.canvas.commit_transaction();
+            },
+            _ => return Err("Usage: bright <delta>".to_string()),
+        }
+        Ok(())
+    });
+
+    // Change the contrast of the selection, or of the whole canvas, around mid-grey, such as
+    // `:contrast 1.5`.
+    ui.add_command("contrast", |_, state, args| {
+        match args.first().map(|factor| factor.parse()) {
+            Some(Ok(factor)) => {
+                let region = filter_region(state);
+                state.canvas.begin_transaction();
+                state.canvas.contrast(region.as_ref(), factor);
+                state.canvas.commit_transaction();
+            },
+            _ => return Err("Usage: contrast <factor>".to_string()),
+        }
+        Ok(())
+    });
+
+    // Snap the colors of the canvas to the nearest colors of the palette, with `:quantize dither`
+    // spreading the error over the neighbouring pixels.
+    ui.add_command("quantize", |_, UiState { canvas, palette, .. }, args| {
+        if palette.is_empty() {
+            return Err("The palette is empty".to_string())
+        }
+
+        let colors = palette.colors();
+        match args.as_slice() {
+            [] => {
+                canvas.begin_transaction();
+                canvas.quantize(&colors);
+                canvas.commit_transaction();
+            },
+            ["dither"] => {
+                canvas.begin_transaction();
+                canvas.quantize_dithered(&colors);
+                canvas.commit_transaction();
+            },
+            _ => return Err("Usage: quantize [dither]".to_string()),
+        }
+        Ok(())
+    });
+
+    // Bind a color to a key of the palette, such as `:color q #ff8800`.
+    // In indexed mode, the pixels which had the color of the key take the new one, and a color
+    // cannot be bound to two keys, which would merge their pixels.
+    ui.add_command("color", |_, UiState { palette, canvas, .. }, args| {
+        match (args.first(), args.get(1).and_then(|hex| color::parse_hex(hex))) {
+            (Some(key), Some(color)) => {
+                let key = CharKeyMod::try_from(key).map_err(|e| e.to_string())?;
+                if canvas.is_indexed() {
+                    let bound = (0..palette.len())
+                        .filter_map(|i| palette.entry(i))
+                        .find(|&(k, c)| k != key && c == color);
+                    if let Some((other, _)) = bound {
+                        let hex = color::to_hex(color);
+                        return Err(format!("{} is already bound to {}", hex, other))
+                    }
+                }
+
+                let index = palette.position(&key);
+                palette.insert(key, color);
+                if canvas.is_indexed() {
+                    canvas.begin_transaction();
+                    match index {
+                        Some(index) => { canvas.recolor(index, color); },
+                        None => { canvas.set_indexed(Some(palette.colors())); },
+                    }
+                    canvas.commit_transaction();
+                }
+            },
+            _ => return Err("Usage: color <key> #RRGGBB".to_string()),
+        }
+        Ok(())
+    });
+
+    // Add the map commands binding keys to a sequence of keys in each mode, such as `:nmap w kkk`
+    // or `:nmap <leader>g :grid<CR>`.
+    let maps = [
+        ("imap", ui::Mode::Insertion),
+        ("nmap", ui::Mode::Normal),
+        ("vmap", ui::Mode::Visual),
+        ("cmap", ui::Mode::Command),
+    ];
+    for &(name, mode) in maps.iter() {
+        ui.add_command(name, move |ui, _, args| {
+            match args.as_slice() {
+                [keys, sequence] => {
+                    ui.try_bind_key(keys, mode, sequence).map_err(|e| e.to_string())?
+                },
+                _ => return Err(format!("Usage: {} <key> <keys>", name)),
+            }
+            Ok(())
+        });
+    }
+
+    // Set the key standing for `<leader>` in the following maps, such as `:leader ,`.
+    ui.add_command("leader", |ui, _, args| {
+        match args.first() {
+            Some(key) => ui.set_leader(CharKeyMod::try_from(key).map_err(|e| e.to_string())?),
+            None => return Err("Usage: leader <key>".to_string()),
+        }
+        Ok(())
+    });
+
+    // Set an option, such as `:set nowrap`.
+    ui.add_command("set", |ui, state, args| {
+        match args.as_slice() {
+            ["wrap"] => ui.set_wrap(true),
+            ["nowrap"] => ui.set_wrap(false),
+            ["snap"] => state.snap = true,
+            ["nosnap"] => state.snap = false,
+            ["swatches"] => state.swatches = true,
+            ["noswatches"] => state.swatches = false,
+            ["rulerline"] => state.ruler_line = true,
+            ["norulerline"] => state.ruler_line = false,
+            ["textres", n] => match n.parse() {
+                Ok(n) if n >= 8 => state.text_resolution = Some(n),
+                _ => return Err("Usage: set textres <n>".to_string()),
+            },
+            ["fps", n] => match n.parse() {
+                Ok(n) => state.fps = n,
+                _ => return Err("Usage: set fps <n>".to_string()),
+            },
+            ["autosave", secs] => match secs.parse() {
+                Ok(secs) => state.autosave = secs,
+                _ => return Err("Usage: set autosave <s>".to_string()),
+            },
+            ["timeoutlen", ms] => match ms.parse() {
+                Ok(ms) => ui.set_timeout(Duration::from_millis(ms)),
+                _ => return Err("Usage: set timeoutlen <ms>".to_string()),
+            },
+            ["blink", ms] => match ms.parse() {
+                Ok(ms) => state.blink = ms,
+                _ => return Err("Usage: set blink <ms>".to_string()),
+            },
+            ["indexed"] if state.palette.is_empty() => {
+                return Err("The palette is empty".to_string())
+            },
+            ["indexed"] => {
+                state.canvas.begin_transaction();
+                state.canvas.set_indexed(Some(state.palette.colors()));
+                state.canvas.commit_transaction();
+            },
+            ["noindexed"] => {
+                state.canvas.begin_transaction();
+                state.canvas.set_indexed(None);
+                state.canvas.commit_transaction();
+            },
+            ["shaderdir", path] => state.shader_dir_to_load = Some(path.to_string()),
+            ["selector", path] => state.selector_to_load = Some((path.to_string(), true)),
+            ["seed", n] => match n.parse() {
+                Ok(n) => state.rng = rng::Rng::new(n),
+                _ => return Err("Usage: set seed <n>".to_string()),
+            },
+            ["bg", color] => match color::parse_hex(color) {
+                Some((r, g, b)) => {
+                    state.background = [r as f32 / 255., g as f32 / 255., b as f32 / 255., 1.0];
+                },
+                None => return Err("Usage: set bg #RRGGBB".to_string()),
+            },
+            _ => return Err(format!("Unknown option: {}", args.join(" "))),
+        }
+        sync_snap(ui, state);
+        Ok(())
+    });
+
+    // Set the number of modifications that can be undone.
+    ui.add_command("undolevels", |_, UiState { canvas, .. }, args| {
+        match args.first().map(|s| s.parse()) {
+            Some(Ok(depth)) => canvas.set_history_depth(depth),
+            _ => return Err("Usage: undolevels <n>".to_string()),
+        }
+        Ok(())
+    });
+
+    ui.add_verb("<Esc>", false, false, |_, UiState { selection, canvas, grab, .. }, _| {
+        selection.clear();
+        *grab = None;
+        canvas.commit_transaction();
+    });
+
+    ui
+}
+
+/// Write the canvas into a PNG file, or into the last file written if no path is given.
+fn write_canvas(ui:&mut Ui<UiState>, state:&mut UiState, path:Option<&str>) -> Result<(), String> {
+    let path = match (path, state.file.as_ref()) {
+        (Some(path), _) => path.to_string(),
+        (None, Some(path)) => path.clone(),
+        (None, None) => return Err("No file name".to_string()),
+    };
+
+    state.canvas.to_rgba_image().save_with_format(&path, ImageFormat::Png)
+        .map_err(|e| format!("Cannot write \"{}\": {}", path, e))?;
+    state.canvas.set_written();
+    ui.set_message(format!("\"{}\" written", path));
+    state.file = Some(path);
+    Ok(())
+}
+
+/// Write the canvas and what is saved along with it into a project file, as `:wproject` does.
+fn write_project<P:AsRef<Path>>(state:&UiState, path:P) -> Result<(), String> {
+    let project = project::Project {
+        frames: state.frames,
+        palette: (0..state.palette.len()).filter_map(|i| state.palette.entry(i)).collect(),
+        color: state.color,
+        selection: state.selection.clone(),
+    };
+
+    let path = path.as_ref();
+    let mut bytes = Vec::new();
+    project::write(&mut bytes, &state.canvas, &project)
+        .map_err(|e| format!("Cannot write \"{}\": {}", path.display(), e))?;
+    fs::write(path, bytes).map_err(|e| format!("Cannot write \"{}\": {}", path.display(), e))
+}
+
+/// Prefix and extension of the swap files the project is saved to while it is edited, next to the
+/// file being edited, their names holding the id of the process of their session and the name of
+/// the file. The swap files of the canvases without file are in the current directory.
+const SWAP_PREFIX : &str = ".vip-swap-";
+const SWAP_EXTENSION : &str = ".vip";
+
+/// Return the swap file of the session of the given process editing the given file.
+fn swap_path(pid:u32, file:Option<&str>) -> PathBuf {
+    match file.map(Path::new) {
+        Some(path) => {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            path.with_file_name(format!("{}{}-{}{}", SWAP_PREFIX, pid, name, SWAP_EXTENSION))
+        },
+        None => PathBuf::from(format!("{}{}{}", SWAP_PREFIX, pid, SWAP_EXTENSION)),
+    }
+}
+
+/// Return the swap files of the given file, with the time they were last written. Whether the
+/// session of a swap file is still running is not known, so the swap files of other running
+/// sessions editing the same file are returned too.
+fn swap_files(file:Option<&str>) -> Vec<(PathBuf, SystemTime)> {
+    let name = file.map(|file| {
+        Path::new(file).file_name().unwrap_or_default().to_string_lossy().to_string()
+    });
+    let dir = match file.and_then(|file| Path::new(file).parent()) {
+        Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            // the rest of the name is the process id, followed by the file name if any
+            let swap_name = entry.file_name().to_string_lossy().to_string();
+            let rest = swap_name.strip_prefix(SWAP_PREFIX)?.strip_suffix(SWAP_EXTENSION)?;
+            let (pid, swap_of) = match rest.find('-') {
+                Some(i) => (&rest[..i], Some(&rest[i + 1..])),
+                None => (rest, None),
+            };
+            if pid.parse::<u32>().is_err() || swap_of != name.as_deref() {
+                return None
+            }
+
+            let time = entry.metadata().and_then(|m| m.modified()).ok()?;
+            Some((entry.path(), time))
+        })
+        .collect()
+}
+
+/// Return when the given file was last written, if it exists.
+fn modified_time(file:Option<&str>) -> Option<SystemTime> {
+    fs::metadata(file?).and_then(|m| m.modified()).ok()
+}
+
+/// Return the most recent swap file of the given file written after the file, which holds
+/// changes a previous session did not write.
+fn find_swap(file:Option<&str>) -> Option<String> {
+    let file_time = modified_time(file);
+    swap_files(file)
+        .into_iter()
+        .filter(|&(_, time)| file_time.map_or(true, |file_time| time > file_time))
+        .max_by_key(|&(_, time)| time)
+        .map(|(path, _)| path.to_string_lossy().to_string())
+}
+
+/// Delete the swap files of the given file written before the file, whose changes are stale.
+fn remove_stale_swaps(file:Option<&str>) {
+    let file_time = match modified_time(file) {
+        Some(time) => time,
+        None => return,
+    };
+
+    for (path, time) in swap_files(file) {
+        if time <= file_time {
+            let _ = fs::remove_file(path);
         }
-    };
+    }
+}
 
-    ui.set_window_event_listener(Some(event_listener));
+/// Run the commands of the configuration file, `./viprc` or else `~/.viprc`, one per line. Lines
+/// starting with `"` are comments. Nothing is done if there is no configuration file.
+fn load_config(ui:&mut Ui<UiState>, state:&mut UiState) {
+    let mut paths = vec!["viprc".to_string()];
+    if let Ok(home) = std::env::var("HOME") {
+        paths.push(format!("{}/.viprc", home));
+    }
 
-    // For each of the H, J, K and L keys, we associate a movement described by a pair of integers.
-    // For each of these pairs, we add an object to the UI event handling system.
-    "hjkl."
-        .chars()
-        .zip([(-1,0),(0,1),(0,-1),(1,0),(0,0)].iter())
-        // h: left, j: down, k: up, l: right.
-        .for_each(|(l, (x,y))| {
-            ui.add_object(l.to_string().as_ref(), move |ui, UiState { canvas,.. }, positions| {
-                positions.insert(ui.cursor());
-                let (w, h) = canvas.size();
-                ui.wrapping_displace(*x, *y, w, h);
-                positions.insert(ui.cursor());
-            });
-        });
+    let config = match paths.iter().find_map(|path| fs::read_to_string(path).ok()) {
+        Some(config) => config,
+        None => return,
+    };
 
-    // TODO: What does it do?
-    ui.add_verb("s", true, |_, UiState { canvas,.. }, positions| {
-        let positions = positions.unwrap();
-        for &(x, y) in positions {
-            canvas.set_pixel_color(x, y, (255, 255, 255));
+    for line in config.lines() {
+        if !line.trim_start().starts_with('"') {
+            ui.run_command_line(state, line);
         }
-    });
+    }
+}
 
-    // Zoom in the canvas.
-    ui.add_verb("<S-+>", false, |_, UiState { zoom, .. }, _| {
-        *zoom += 0.1;
-    });
+/// Wait for the rest of the time given to a frame started at the given instant, so as not to use a
+/// whole CPU core. Without limit of frames per second, return at once.
+fn wait_for_next_frame(fps:u32, start:Instant) {
+    if fps > 0 {
+        let budget = Duration::from_secs(1) / fps;
+        let spent = start.elapsed();
+        if spent < budget {
+            std::thread::sleep(budget - spent);
+        }
+    }
+}
 
-    // Zoom out the canvas
-    ui.add_verb("-", false, |_, UiState { zoom, .. }, _| {
-        *zoom -= 0.1;
-    });
+/// The code of the shaders, built into the program so that it runs from any directory, by path
+/// relative to `src`.
+const SHADERS : &[(&str, &str)] = &[
+    ("canvas/normal.vert", include_str!("canvas/normal.vert")),
+    ("canvas/normal.frag", include_str!("canvas/normal.frag")),
+    ("canvas/onion.frag", include_str!("canvas/onion.frag")),
+    ("text/text.vert", include_str!("text/text.vert")),
+    ("text/text.frag", include_str!("text/text.frag")),
+    ("selection.vert", include_str!("selection.vert")),
+    ("selection.frag", include_str!("selection.frag")),
+    ("grid.vert", include_str!("grid.vert")),
+    ("grid.frag", include_str!("grid.frag")),
+    ("cursor.vert", include_str!("cursor.vert")),
+    ("cursor.frag", include_str!("cursor.frag")),
+    ("background.vert", include_str!("background.vert")),
+    ("background.frag", include_str!("background.frag")),
+    ("swatch.vert", include_str!("swatch.vert")),
+    ("swatch.frag", include_str!("swatch.frag")),
+];
+
+/// Return the code of a shader: the one of the file of the same path in the given directory if
+/// it can be read, to edit the shaders without building the program again, else the built-in one.
+fn shader_source(dir:Option<&str>, path:&str) -> String {
+    dir.and_then(|dir| fs::read_to_string(std::path::Path::new(dir).join(path)).ok())
+        .or_else(|| {
+            SHADERS.iter().find(|(name, _)| *name == path).map(|(_, code)| code.to_string())
+        })
+        .unwrap_or_default()
+}
 
-    // Enter command mode.
-    ui.add_verb(":", false, |ui, _, _| {
-        ui.set_mode(ui::Mode::Command);
-    });
+/// Retrieve the code of the vertex and fragment shaders and compile the corresponding shader
+/// program. The error names the shaders and holds the compilation log.
+fn compile_shader_program(dir:Option<&str>, vert: &str, frag: &str)
+    -> Result<Program<Semantics, (), ShaderInterface>, String> {
+    let vert_shader = shader_source(dir, vert);
+    let frag_shader = shader_source(dir, frag);
+    Program::from_strings(None, &vert_shader, None, &frag_shader)
+        .map(|program| program.ignore_warnings())
+        .map_err(|e| format!("Cannot compile {} and {}: {}", vert, frag, e))
+}
 
-    // Enter insert mode. If the current mode was visual, select the pixels that were highlighted.
-    ui.add_verb("i", false, |ui, UiState { selection, visual_type, .. }, _| {
-        if ui.get_mode() == ui::Mode::Visual {
-            selection.clear();
-            let (a, b) = ui.get_selection();
-            visual_type.select_pixels(selection, a, b);
-        }
-        ui.set_mode(ui::Mode::Insertion);
-    });
+/// The shader programs everything is drawn with.
+struct Programs {
+    canvas: Program<Semantics, (), ShaderInterface>,
+    onion: Program<Semantics, (), ShaderInterface>,
+    text: Program<Semantics, (), ShaderInterface>,
+    selection: Program<Semantics, (), ShaderInterface>,
+    grid: Program<Semantics, (), ShaderInterface>,
+    cursor: Program<Semantics, (), ShaderInterface>,
+    checker: Program<Semantics, (), ShaderInterface>,
+    swatch: Program<Semantics, (), ShaderInterface>,
+}
 
-    // Enter square visual mode.
-    ui.add_verb("v", false, |ui, UiState { visual_type, .. }, _| {
-        *visual_type = VisualType::Square;
-        ui.set_mode(ui::Mode::Visual);
-    });
+impl Programs {
+    /// Compile every program, with the shaders of the given directory when there are some.
+    fn compile(dir:Option<&str>) -> Result<Self, String> {
+        Ok(Self {
+            canvas: compile_shader_program(dir, "canvas/normal.vert", "canvas/normal.frag")?,
+            onion: compile_shader_program(dir, "canvas/normal.vert", "canvas/onion.frag")?,
+            text: compile_shader_program(dir, "text/text.vert", "text/text.frag")?,
+            selection: compile_shader_program(dir, "selection.vert", "selection.frag")?,
+            grid: compile_shader_program(dir, "grid.vert", "grid.frag")?,
+            cursor: compile_shader_program(dir, "cursor.vert", "cursor.frag")?,
+            checker: compile_shader_program(dir, "background.vert", "background.frag")?,
+            swatch: compile_shader_program(dir, "swatch.vert", "swatch.frag")?,
+        })
+    }
+}
 
-    // Enter circle visual mode.
-    ui.add_verb("V", false, |ui, UiState { visual_type, .. }, _| {
-        *visual_type = VisualType::Circle;
-        ui.set_mode(ui::Mode::Visual);
-    });
+/// Build the quad on which a canvas of the given size is drawn, one unit per pixel.
+fn canvas_quad(size:(usize, usize)) -> [Vertex; 6] {
+    frame_quad(size, 1, 0)
+}
 
-    // TODO: What does it do?
-    ui.add_verb("H", false, |_, UiState { center,.. }:&mut UiState, _| {
-        center.0 -= 1.0;
-    });
-    ui.add_verb("J", false, |_, UiState { center,.. }:&mut UiState, _| {
-        center.1 += 1.0;
-    });
-    ui.add_verb("K", false, |_, UiState { center,.. }:&mut UiState, _| {
-        center.1 -= 1.0;
-    });
-    ui.add_verb("L", false, |_, UiState { center,.. }:&mut UiState, _| {
-        center.0 += 1.0;
-    });
+/// Build the quad on which one of the frames of a canvas of the given size is drawn, one unit per
+/// pixel, the canvas being split into frames from left to right.
+fn frame_quad((w, h):(usize, usize), frames:usize, frame:usize) -> [Vertex; 6] {
+    let fw = w / frames.max(1);
+    let (u0, u1) = ((frame * fw) as f32 / w as f32, ((frame + 1) * fw) as f32 / w as f32);
+    let (w, h) = (fw as f32, h as f32);
+    [
+        Vertex { pos:VertexPosition::new([0.0, 0.0]), texPos:TexPosition::new([ u0,0.0]) },
+        Vertex { pos:VertexPosition::new([  w, 0.0]), texPos:TexPosition::new([ u1,0.0]) },
+        Vertex { pos:VertexPosition::new([0.0,   h]), texPos:TexPosition::new([ u0,1.0]) },
+        Vertex { pos:VertexPosition::new([0.0,   h]), texPos:TexPosition::new([ u0,1.0]) },
+        Vertex { pos:VertexPosition::new([  w,   h]), texPos:TexPosition::new([ u1,1.0]) },
+        Vertex { pos:VertexPosition::new([  w, 0.0]), texPos:TexPosition::new([ u1,0.0]) },
+    ]
+}
 
-    // Add the quit commands
-    ui.add_command("q", |ui, _, _| {
-        ui.close()
-    });
+/// Build the quad on which a canvas of the given size is drawn three by three around itself, the
+/// texture repeating outside of the canvas.
+fn tiled_quad((w, h):(usize, usize)) -> [Vertex; 6] {
+    let (w, h) = (w as f32, h as f32);
+    [
+        Vertex { pos:VertexPosition::new([ -w,  -h]), texPos:TexPosition::new([-1.0,-1.0]) },
+        Vertex { pos:VertexPosition::new([2.*w, -h]), texPos:TexPosition::new([ 2.0,-1.0]) },
+        Vertex { pos:VertexPosition::new([ -w, 2.*h]), texPos:TexPosition::new([-1.0, 2.0]) },
+        Vertex { pos:VertexPosition::new([ -w, 2.*h]), texPos:TexPosition::new([-1.0, 2.0]) },
+        Vertex { pos:VertexPosition::new([2.*w,2.*h]), texPos:TexPosition::new([ 2.0, 2.0]) },
+        Vertex { pos:VertexPosition::new([2.*w, -h]), texPos:TexPosition::new([ 2.0,-1.0]) },
+    ]
+}
 
-    ui.add_command("quit", |ui, _, _| {
-        ui.close()
-    });
+/// Load an atlas of the selection borders, which must be `sel::ATLAS_SIZE` pixels wide and high.
+fn load_selector(path:&str) -> Result<image::RgbaImage, String> {
+    let atlas = open(path).map_err(|e| e.to_string())?.to_rgba();
+    if atlas.dimensions() != (sel::ATLAS_SIZE, sel::ATLAS_SIZE) {
+        return Err(format!("the image must be {}x{}", sel::ATLAS_SIZE, sel::ATLAS_SIZE))
+    }
+    Ok(atlas)
+}
 
-    // Empty action.
-    ui.add_verb("_", true, |_,_,_| {});
+/// Upload an image to a texture of the same size.
+fn upload_image(tex:&Texture<Dim2, NormRGBA8UI>, img:&image::RgbaImage)
+    -> Result<(), TextureError> {
+    let texels : Vec<Pixel> = img.pixels().map(|&image::Rgba([r, g, b, a])| (r, g, b, a)).collect();
+    tex.upload(GenMipmaps::No, &texels)
+}
 
-    // Add default key bindings for arrows in insert mode.
-    ui.bind_key("<Left>", ui::Mode::Insertion, "<Esc>hi");
-    ui.bind_key("<Right>", ui::Mode::Insertion, "<Esc>li");
-    ui.bind_key("<Down>", ui::Mode::Insertion, "<Esc>ji");
-    ui.bind_key("<Up>", ui::Mode::Insertion, "<Esc>ki");
+/// Create the texture holding the pixels of the canvas and the quad it is drawn on. Both must be
+/// created again when the size of the canvas changes.
+fn create_canvas_texture<C:GraphicsContext>(ctx:&mut C, canvas:&Canvas, sampler:Sampler)
+    -> (Texture<Dim2, NormRGBA8UI>, Tess)
+{
+    let (w, h) = canvas.size();
 
-    // Add the imap command for key mapping in insert mode.
-    ui.add_command("imap", |ui, _, args| {
-        ui.bind_key(args[0], ui::Mode::Insertion, args[1]);
-    });
+    let tex : Texture<Dim2, NormRGBA8UI> = Texture::new(ctx, [w as u32, h as u32], 0, sampler)
+        .expect("Cannot create texture");
 
-    ui.add_verb("<Esc>", false, |_, UiState { selection, .. }, _| {
-        selection.clear();
-    });
+    tex.upload(GenMipmaps::No, canvas)
+        .expect("Cannot upload texture");
 
-    ui
+    let tess = TessBuilder::new(ctx)
+        .add_vertices(canvas_quad((w, h)))
+        .set_mode(Mode::Triangle)
+        .build()
+        .unwrap();
+
+    (tex, tess)
 }
 
-/// Retrieve the code from the vertex and fragment shader files and compile the corresponding
-/// shader program.
-fn compile_shader_program(vert: &str, frag: &str) -> Program<Semantics, (), ShaderInterface> {
-    let vert_shader = fs::read_to_string(vert).unwrap();
-    let frag_shader = fs::read_to_string(frag).unwrap();
-    Program::from_strings(None, &vert_shader, None, &frag_shader)
-        .expect("Couldn't compile OpenGL program")
-        .ignore_warnings()
+/// Read the command line arguments, `[W H] [FILE]`: the size of the canvas, 16x16 by default,
+/// and the file it is loaded from if it exists, else saved to.
+fn parse_args(args:&[String]) -> Result<((usize, usize), Option<String>), String> {
+    let size = |w:&str, h:&str| match (w.parse(), h.parse()) {
+        (Ok(w), Ok(h)) if w > 0 && h > 0 => Ok((w, h)),
+        _ => Err(format!("Invalid canvas size: {} {}", w, h)),
+    };
+
+    match args {
+        [] => Ok(((16, 16), None)),
+        [file] => Ok(((16, 16), Some(file.clone()))),
+        [w, h] => Ok((size(w, h)?, None)),
+        [w, h, file] => Ok((size(w, h)?, Some(file.clone()))),
+        _ => Err("Too many arguments".to_string()),
+    }
 }
 
 fn main() {
     const WIDTH : f32 = 800.0;
     const HEIGHT : f32 = 600.0;
 
-    const TRI_VERT : [canvas::Vertex; 6] = {
-        [
-            Vertex { pos:VertexPosition::new([ 0.0, 0.0]), texPos:TexPosition::new([0.0,0.0]) },
-            Vertex { pos:VertexPosition::new([16.0, 0.0]), texPos:TexPosition::new([1.0,0.0]) },
-            Vertex { pos:VertexPosition::new([ 0.0,16.0]), texPos:TexPosition::new([0.0,1.0]) },
-            Vertex { pos:VertexPosition::new([ 0.0,16.0]), texPos:TexPosition::new([0.0,1.0]) },
-            Vertex { pos:VertexPosition::new([16.0,16.0]), texPos:TexPosition::new([1.0,1.0]) },
-            Vertex { pos:VertexPosition::new([16.0, 0.0]), texPos:TexPosition::new([1.0,0.0]) },
-        ]
+    // Height in canvas pixels of the lines of the text drawn by `:text`.
+    const STAMP_HEIGHT : f32 = 8.0;
+
+    // Size of a canvas pixel in the frame preview, and distance of the preview to the window
+    // edges, in screen pixels.
+    const PREVIEW_PIXEL_SIZE : f32 = 4.0;
+    const PREVIEW_MARGIN : f32 = 10.0;
+
+    let args : Vec<String> = std::env::args().skip(1).collect();
+    let ((w, h), file) = parse_args(&args).unwrap_or_else(|e| {
+        eprintln!("{}\nUsage: vip [W H] [FILE]", e);
+        std::process::exit(1)
+    });
+
+    // an existing file is loaded, whatever the size given
+    let pattern = match &file {
+        Some(path) if std::path::Path::new(path).exists() => match open(path) {
+            Ok(img) => Canvas::from_rgba_image(&img.to_rgba()),
+            Err(e) => {
+                eprintln!("Cannot open \"{}\": {}", path, e);
+                std::process::exit(1)
+            },
+        },
+        _ => Canvas::new(w, h),
     };
 
     let dim = WindowDim::Windowed(WIDTH as u32, HEIGHT as u32);
@@ -246,24 +2554,19 @@ fn main() {
     let mut glfw = GlfwSurface::new(dim, "VIsual Pixels", opt)
         .expect("Couldn't create glfw window");
 
-    let tess = TessBuilder::new(&mut glfw)
-        .add_vertices(TRI_VERT)
-        .set_mode(Mode::Triangle)
-        .build()
-        .unwrap();
-
-    let pipestate = PipelineState::new()
-        .set_clear_color([0.3, 0.3, 0.3, 1.0])
-        .enable_clear_color(true);
+    let (content_scale, _) = glfw.window.get_content_scale();
+    let (fw, fh) = glfw.window.get_framebuffer_size();
 
-    let program = compile_shader_program("src/canvas/normal.vert", "src/canvas/normal.frag");
-    let text_program = compile_shader_program("src/text/text.vert", "src/text/text.frag");
-    let select_program = compile_shader_program("src/selection.vert", "src/selection.frag");
+    // a built-in shader which does not compile is reported and ends the program
+    let mut programs = Programs::compile(None).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    });
 
     let mut framebuffer = glfw.back_buffer().unwrap();
 
     let mut textb = text::TextRendererBuilder::for_resolution(64);
-    let fid = textb.add_font("/usr/share/fonts/TTF/Hack-Regular.ttf").unwrap();
+    let mut fid = textb.add_font_bytes(text::DEFAULT_FONT).unwrap();
 
     let text_sampler = Sampler {
         wrap_r: Wrap::ClampToEdge,
@@ -273,7 +2576,7 @@ fn main() {
         mag_filter: MagFilter::Linear,
         depth_comparison: None,
     };
-    let text = textb.build(&mut glfw, text_sampler)
+    let mut text = textb.build(&mut glfw, text_sampler)
         .expect("Cannot load fonts");
 
 
@@ -281,6 +2584,11 @@ fn main() {
         .set_blending(Some((Equation::Additive, Factor::SrcAlpha, Factor::SrcAlphaComplement)))
         .set_depth_test(None);
 
+    // the cursor border takes the complement of the color it is drawn over
+    let cursor_render_state = RenderState::default()
+        .set_blending(Some((Equation::Additive, Factor::DestColorComplement, Factor::Zero)))
+        .set_depth_test(None);
+
     let mut text_tess;
 
     let sampler = Sampler {
@@ -292,25 +2600,27 @@ fn main() {
         depth_comparison : None,
     };
 
-    let (width, height) = (16, 16);
-
-    let tex : Texture<Dim2, NormRGB8UI> = Texture::new(&mut glfw, [width, height], 0, sampler)
-        .expect("Cannot create texture");
-
-    let pattern = Canvas::new(width as usize, height as usize);
+    // the canvas texture repeats, for the tiled view
+    let canvas_sampler = Sampler { wrap_s: Wrap::Repeat, wrap_t: Wrap::Repeat, ..sampler };
 
-    tex.upload(GenMipmaps::No, &pattern)
-        .expect("Cannot upload texture");
+    let (mut tex, mut tess) = create_canvas_texture(&mut glfw, &pattern, canvas_sampler);
 
 
     let mut ui = create_ui();
 
-    let mut palette = HashMap::new();
+    let mut palette = palette::Palette::default();
     palette.insert(CharKeyMod::from("a"), (255, 0, 0));
     palette.insert(CharKeyMod::from("z"), (0, 255, 0));
     palette.insert(CharKeyMod::from("e"), (0, 0, 255));
 
     let mut state = UiState {
+        color: (255, 0, 0),
+        palette_index: 0,
+        color_history: palette::ColorHistory::default(),
+        recent_colors: Vec::new(),
+        opacity: 1.0,
+        mirror: (false, false),
+        brush: (1, false),
         must_resize: false,
         scale: (1.0 / WIDTH, 1.0 / HEIGHT),
         zoom: 1.0,
@@ -319,33 +2629,165 @@ fn main() {
         visual_type: VisualType::Square,
         palette,
         window_size: (WIDTH, HEIGHT),
-        selection: HashSet::new(),
+        content_scale,
+        framebuffer_size: (fw as u32, fh as u32),
+        selection: sel::Selection::default(),
+        saved_selections: HashMap::new(),
+        grab: None,
+        font_to_load: None,
+        text_to_stamp: None,
+        text_resolution: None,
+        shader_dir_to_load: None,
+        selector_to_load: Some(("selecteur.png".to_string(), false)),
+        fps: 60,
+        must_redraw: true,
+        background: [0.3, 0.3, 0.3, 1.0],
+        blink: 500,
+        autosave: 30,
+        swap_to_recover: None,
+        rng: rng::Rng::new(0),
+        file,
+        buffers: vec![Buffer::new()],
+        buffer: 0,
+        split: None,
+        must_rebuild_canvas: false,
+        clipboard: Vec::new(),
+        grid: false,
+        guides: Vec::new(),
+        snap: false,
+        swatches: true,
+        ruler_line: true,
+        minimap: false,
+        tile: false,
+        checker: background::Checker::default(),
+        pointer: (0.0, 0.0),
+        painting: false,
+        pointer_pixel: None,
+        panning: false,
+        pointer_delta: (0.0, 0.0),
+        frames: 1,
+        playback: None,
+        frame: 0,
+        onion: false,
     };
 
-    let img = open("selecteur.png").unwrap();
-    let raw : Vec<(u8, u8, u8, u8)> =
-        match img {
-            DynamicImage::ImageRgba8(img) => {
-                img
-                    .into_vec()
-                    .chunks(4)
-                    .map(|l| {
-                        match l {
-                            [r,b,g,a] => (*r,*b,*g,*a),
-                            _ => panic!("not normal"),
-                        }
-                    })
-                    .collect()
-            },
-            _ => { unimplemented!("Error while loading selection image") },
-        };
-    let tex_sel : Texture<Dim2, NormRGBA8UI> = Texture::new(&mut glfw, [256, 256], 0, sampler)
+    load_config(&mut ui, &mut state);
+
+    // the swap file of a session which ended badly can be recovered unless the file was written
+    // since, the one of this session being named after its process and the file being edited
+    remove_stale_swaps(state.file.as_deref());
+    state.swap_to_recover = find_swap(state.file.as_deref()).map(|swap| (swap, state.file.clone()));
+    if let Some((swap, _)) = &state.swap_to_recover {
+        ui.set_message(format!("Found \"{}\", :recover to load it", swap));
+    }
+    let pid = std::process::id();
+    let mut swap_file = swap_path(pid, state.file.as_deref());
+    let mut last_autosave = Instant::now();
+    let mut active = false;
+
+    // the selection borders are drawn with the generated atlas until the atlas image is loaded
+    let atlas_size = [sel::ATLAS_SIZE, sel::ATLAS_SIZE];
+    let tex_sel : Texture<Dim2, NormRGBA8UI> = Texture::new(&mut glfw, atlas_size, 0, sampler)
         .expect("Cannot create selection texture");
-    tex_sel.upload(GenMipmaps::No, raw.as_ref())
-        .expect("Cannot upload selection texture");
+    upload_image(&tex_sel, &sel::generated_atlas()).expect("Cannot upload selection texture");
+
+    let mut last_frame = Instant::now();
+    let mut select_cache : Option<(SelectKey, Tess)> = None;
+    let mut split_canvas : Option<(usize, Texture<Dim2, NormRGBA8UI>, Tess)> = None;
+    let mut text_resolution = 64;
+    let mut text_scale = 1.0;
+    let mut blink_start = Instant::now();
+    let mut cursor_visible = true;
 
     'main_loop: loop {
         if !ui.input(&mut glfw, &mut state) { break 'main_loop }
+        pan_view(&mut state);
+
+        // every color made current is remembered, unless it was stepped to in the history
+        state.color_history.record(state.color, COLOR_HISTORY);
+
+        // the canvas is written to the swap file once in a while when something happens
+        active |= ui.received_events();
+        let autosave = Duration::from_secs(state.autosave);
+        if state.autosave > 0 && active && last_autosave.elapsed() >= autosave {
+            // the swap file follows the file being edited, the one left behind being removed
+            let path = swap_path(pid, state.file.as_deref());
+            if path != swap_file {
+                let _ = fs::remove_file(&swap_file);
+                swap_file = path;
+            }
+
+            // the swap file is a project, so that the layers are recovered too
+            if let Err(e) = write_project(&state, &swap_file) {
+                ui.set_message(e);
+            }
+            last_autosave = Instant::now();
+            active = false;
+        }
+
+        let now = Instant::now();
+        let dt = now - last_frame;
+        last_frame = now;
+
+        // the cursor is shown again as soon as something happens, then blinks
+        if ui.received_events() {
+            blink_start = now;
+        }
+        let visible = cursor::is_visible((now - blink_start).as_millis(), state.blink);
+
+        // nothing is drawn until an event, the playback of the frames or the blink of the cursor
+        // changes what is displayed
+        if ui.received_events() || state.playback.is_some() || visible != cursor_visible {
+            state.must_redraw = true;
+        }
+        cursor_visible = visible;
+        if !state.must_redraw {
+            // with nothing to draw, wait for an event, or for the cursor to blink, the typed keys
+            // to time out or the autosave to be due
+            let blink = cursor::until_blink((now - blink_start).as_millis(), state.blink)
+                .map(|ms| now + Duration::from_millis(ms));
+            let save = Some(last_autosave + autosave).filter(|_| state.autosave > 0 && active);
+            let deadline = [blink, ui.pending_deadline(), save].iter().flatten().min().cloned();
+            match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    glfw.window.glfw.wait_events_timeout(timeout.as_secs_f64());
+                },
+                None => glfw.window.glfw.wait_events(),
+            }
+            continue 'main_loop
+        }
+        state.must_redraw = false;
+
+        let (frames, size) = (state.frames, state.canvas.size());
+
+        // the previous frame tinted in red and the next one tinted in green
+        let onion_tess : Vec<(Tess, [f32; 4])> = if state.onion && frames > 1 {
+            let previous = (state.frame + frames - 1) % frames;
+            let next = (state.frame + 1) % frames;
+            [(previous, [1.0, 0.0, 0.0, 0.3]), (next, [0.0, 1.0, 0.0, 0.3])]
+                .iter()
+                .map(|&(frame, tint)| {
+                    let tess = TessBuilder::new(&mut glfw)
+                        .add_vertices(frame_quad(size, frames, frame))
+                        .set_mode(Mode::Triangle)
+                        .build()
+                        .unwrap();
+                    (tess, tint)
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let preview_tess = state.playback.as_mut().map(|playback| {
+            playback.advance(dt, frames);
+            TessBuilder::new(&mut glfw)
+                .add_vertices(frame_quad(size, frames, playback.frame))
+                .set_mode(Mode::Triangle)
+                .build()
+                .unwrap()
+        });
 
 
         if state.must_resize {
@@ -353,75 +2795,435 @@ fn main() {
             state.must_resize = false;
         }
 
+        if let Some(path) = state.font_to_load.take() {
+            match text.add_font(&mut glfw, &path) {
+                Some(id) => fid = id,
+                None => ui.set_message(format!("Cannot load \"{}\"", path)),
+            }
+        }
+
+        // the glyphs are rasterized with more pixels on HiDPI screens to stay sharp
+        let requested_resolution = state.text_resolution.take();
+        if requested_resolution.is_some() || text_scale != state.content_scale {
+            text_resolution = requested_resolution.unwrap_or(text_resolution);
+            text_scale = state.content_scale;
+
+            let resolution = (text_resolution as f32 * text_scale).round() as u32;
+            if text.rebuild(&mut glfw, resolution).is_none() {
+                ui.set_message(format!("Cannot render the text with resolution {}", resolution));
+            }
+        }
+
+        if let Some(string) = state.text_to_stamp.take() {
+            if let Some(font) = text.font(fid) {
+                let bitmap = text::rasterize(&string, &font, STAMP_HEIGHT);
+                let size = (bitmap.width() as usize, bitmap.height() as usize);
+                stamp(&ui, &mut state, &bitmap, size);
+            }
+        }
+
+        // shaders which do not compile are reported, the previous ones being kept
+        if let Some(dir) = state.shader_dir_to_load.take() {
+            match Programs::compile(Some(&dir)) {
+                Ok(new_programs) => programs = new_programs,
+                Err(e) => ui.set_message(e),
+            }
+            state.must_redraw = true;
+        }
+
+        if let Some((path, asked)) = state.selector_to_load.take() {
+            match load_selector(&path) {
+                Ok(atlas) => {
+                    upload_image(&tex_sel, &atlas).expect("Cannot upload selection texture")
+                },
+                Err(e) if asked => ui.set_message(format!("Cannot load \"{}\": {}", path, e)),
+                Err(_) => {},
+            }
+            // the selection vertices do not change, but their texture does
+            state.must_redraw = true;
+        }
+
+        // the canvas of the other pane is uploaded when it shows another buffer than the edited
+        // one, and again when the edited buffer changes
+        if state.must_rebuild_canvas {
+            split_canvas = None;
+        }
+        match state.split {
+            Some(split) if split.buffer != state.buffer => {
+                if split_canvas.as_ref().map(|(buffer, _, _)| *buffer) != Some(split.buffer) {
+                    let canvas = &state.buffers[split.buffer].canvas;
+                    let (split_tex, split_tess) =
+                        create_canvas_texture(&mut glfw, canvas, canvas_sampler);
+                    split_canvas = Some((split.buffer, split_tex, split_tess));
+                }
+            },
+            _ => split_canvas = None,
+        }
+
+        if state.must_rebuild_canvas {
+            let (new_tex, new_tess) =
+                create_canvas_texture(&mut glfw, &state.canvas, canvas_sampler);
+            tex = new_tex;
+            tess = new_tess;
+            state.must_rebuild_canvas = false;
+        }
+
 
-        tex.upload(GenMipmaps::No, state.canvas.as_ref()).expect("Cannot upload texture");
+        state.canvas.upload_dirty(&tex).expect("Cannot upload texture");
 
-        let verts = text.render_text(
-            format!("{:?}:{}", ui.get_mode(), ui.get_buffer()),
-            (0.0, state.window_size.1 - 10.0),
-            fid);
+        // the mode at the start of the status line is tinted, the rest is white
+        let status = truncate_status(&text, fid, status_line(&ui, &state), state.window_size.0);
+        let mode = format!("{:?}", ui.get_mode());
+        let status_pos = (0.0, state.window_size.1 - 10.0);
+        let mut verts = match status.strip_prefix(&mode) {
+            Some(rest) => {
+                let color = mode_color(ui.get_mode());
+                let mut verts = text.render_colored_text(&mode, status_pos, fid, color);
+                let rest_pos = (status_pos.0 + text.text_width(&mode, fid), status_pos.1);
+                verts.extend(text.render_text(rest, rest_pos, fid));
+                verts
+            },
+            None => text.render_text(status, status_pos, fid),
+        };
+
+        // the recently used colors are shown over the palette, each swatch being labelled with
+        // the key it is picked with, in black over the light colors
+        let mut swatches = Vec::new();
+        if state.swatches {
+            let bar_pos = (SWATCH_MARGIN.0, state.window_size.1 - SWATCH_MARGIN.1 - swatch::SIZE);
+            let recent_pos = (bar_pos.0, bar_pos.1 - swatch::SIZE - swatch::SPACING);
+            let keys = (0..state.palette.len())
+                .filter_map(|i| state.palette.entry(i))
+                .map(|(key, _)| key.to_string());
+
+            swatches.extend(state.palette.colors()
+                .into_iter()
+                .zip(swatch::positions(state.palette.len(), bar_pos))
+                .zip(keys));
+            swatches.extend(state.recent_colors
+                .iter()
+                .cloned()
+                .zip(swatch::positions(state.recent_colors.len(), recent_pos))
+                .zip((1..).map(|n:usize| n.to_string())));
+        }
+        for &(((r, g, b), (x, y)), ref label) in &swatches {
+            let luma = 0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32;
+            let color = if luma > 128.0 { [0, 0, 0, 255] } else { text::WHITE };
+            let label_pos = (x + 4.0, y + swatch::SIZE - 4.0);
+            verts.extend(text.render_colored_text(label, label_pos, fid, color));
+        }
+        let swatch_tess = if swatches.is_empty() {
+            None
+        } else {
+            let (colors, positions) : (Vec<_>, Vec<_>) = swatches.iter().map(|(cp, _)| *cp).unzip();
+            let swatch_verts = swatch::vertice_from_colors(&colors, &positions, state.color);
+            TessBuilder::new(&mut glfw)
+                .add_vertices(&swatch_verts[..])
+                .set_mode(Mode::Triangle)
+                .build()
+                .ok()
+        };
 
         text_tess = TessBuilder::new(&mut glfw)
             .add_vertices(&verts[..])
             .set_mode(Mode::Triangle)
             .build().ok();
 
-        let set = if ui.get_mode() == ui::Mode::Visual {
-            let (a, b) = ui.get_selection();
-            let mut set = HashSet::new();
-            state.visual_type.select_pixels(&mut set, a, b);
-            set
-        } else if state.selection.is_empty() {
-            [ui.cursor()].iter().cloned().collect()
+        // the selection is built again only when it changed, the cursor being drawn on its own,
+        // and the line measured in ruler mode is drawn the same way
+        let visual = ui.get_mode() == ui::Mode::Visual;
+        let key = (
+            state.canvas.generation(),
+            state.selection.generation(),
+            if visual {
+                Some((state.visual_type, ui.cursor(), ui.get_anchor(), state.ruler_line))
+            } else {
+                None
+            });
+        if select_cache.as_ref().map(|(k, _)| *k) != Some(key) {
+            let set = if !visual && state.selection.is_empty() {
+                HashSet::new()
+            } else if visual && state.visual_type == VisualType::Ruler {
+                if state.ruler_line { ruler_line(&ui) } else { HashSet::new() }
+            } else {
+                active_selection(&ui, &state)
+            };
+            // the neighbours of every pixel are looked up, which a dense bitmap does much faster
+            let bitmap = DenseBitMap2D::from_set(&set, state.canvas.size());
+            let tess = TessBuilder::new(&mut glfw)
+                .add_vertices(&sel::vertice_from_selection(&bitmap, &state.canvas))
+                .set_mode(Mode::Triangle)
+                .build()
+                .unwrap();
+            select_cache = Some((key, tess));
+        }
+        let select_tess = &select_cache.as_ref().unwrap().1;
+
+        // the part of the canvas seen in the focused pane is outlined on the minimap
+        let minimap_tess = if state.minimap {
+            let (x0, (w, h)) = focused_pane(&state);
+            let (cw, ch) = state.canvas.size();
+            let clamp = |(x, y):(f32, f32)| (x.max(0.0).min(cw as f32), y.max(0.0).min(ch as f32));
+            let top_left = clamp(screen_to_canvas(&state, (x0, 0.0)));
+            let bottom_right = clamp(screen_to_canvas(&state, (x0 + w, h)));
+            TessBuilder::new(&mut glfw)
+                .add_vertices(&grid::vertice_from_rect(top_left, bottom_right))
+                .set_mode(Mode::Line)
+                .build()
+                .ok()
+        } else {
+            None
+        };
+
+        let grid_tess = if state.grid {
+            let spacing = grid::spacing(PIXEL_SIZE * state.zoom / 2.0);
+            TessBuilder::new(&mut glfw)
+                .add_vertices(&grid::vertice_from_size(state.canvas.size(), spacing))
+                .set_mode(Mode::Line)
+                .build()
+                .ok()
         } else {
-            state.selection.clone()
+            None
         };
 
-        let select_tess = TessBuilder::new(&mut glfw)
-            .add_vertices(&sel::vertice_from_selection(&set, &state.canvas))
-            .set_mode(Mode::Triangle)
-            .build()
-            .unwrap();
+        let guide_tess = if state.guides.is_empty() {
+            None
+        } else {
+            TessBuilder::new(&mut glfw)
+                .add_vertices(&grid::vertice_from_guides(&state.guides, state.canvas.size()))
+                .set_mode(Mode::Line)
+                .build()
+                .ok()
+        };
+
+        let tile_tess = if state.tile {
+            TessBuilder::new(&mut glfw)
+                .add_vertices(tiled_quad(state.canvas.size()))
+                .set_mode(Mode::Triangle)
+                .build()
+                .ok()
+        } else {
+            None
+        };
+        let canvas_tess = tile_tess.as_ref().unwrap_or(&tess);
+
+        let cursor_tess = if cursor_visible {
+            TessBuilder::new(&mut glfw)
+                .add_vertices(&cursor::vertice_from_cell(ui.cursor()))
+                .set_mode(Mode::Triangle)
+                .build()
+                .ok()
+        } else {
+            None
+        };
+
+        // draw the focused pane, then the other one if the window is split, then what is drawn
+        // over the whole window
+        let (pane_x, pane_size) = focused_pane(&state);
+        let pane_viewport = |x:f32| {
+            let ratio = state.framebuffer_size.0 as f32 / state.window_size.0;
+            Viewport::Specific {
+                x: (x * ratio).round() as u32,
+                y: 0,
+                width: (pane_size.0 * ratio).round() as u32,
+                height: state.framebuffer_size.1,
+            }
+        };
+
+        let pipestate = PipelineState::new()
+            .set_clear_color(state.background)
+            .enable_clear_color(true)
+            .set_viewport(pane_viewport(pane_x));
 
-        // draw
         glfw.pipeline_builder().pipeline(&framebuffer, &pipestate, |pipeline, mut shd_gate| {
             let drawing_buffer = pipeline.bind_texture(&tex);
-            let font_atlas = pipeline.bind_texture(&text.atlas);
             let select_atlas = pipeline.bind_texture(&tex_sel);
 
-            let text_view = {
-                let center_x = (state.window_size.0) / 2.0;
-                let center_y = (state.window_size.1) / 2.0;
+            let canvas_view = to_raw(view_matrix(state.zoom, state.center, pane_size));
 
-                to_raw(scale(state.scale.0, -state.scale.1) * translate(-center_x, -center_y))
-            };
+            // render checkerboard
+            if state.checker.enabled {
+                shd_gate.shade(&programs.checker, |iface, mut rdr_gate| {
+                    let uniform = iface.query();
+                    uniform.ask("view").unwrap().update(canvas_view);
+                    uniform.ask("size").unwrap().update(state.checker.size);
+                    uniform.ask("light").unwrap().update(state.checker.light);
+                    uniform.ask("dark").unwrap().update(state.checker.dark);
 
-            let canvas_view = {
-                let scale_x = state.scale.0 * (width as f32) * state.zoom;
-                let scale_y = -state.scale.1 * (height as f32) * state.zoom;
+                    rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(canvas_tess) );
+                });
+            }
 
-                to_raw(scale(scale_x, scale_y) * translate(state.center.0, state.center.1))
+            // render the frames around the edited one faintly behind it, where it is transparent
+            let onion_view = {
+                let (x, _) = frame_columns(&state);
+                to_raw(view_matrix(state.zoom, state.center, pane_size) * translate(x as f32, 0.0))
             };
 
+            for (onion_tess, tint) in onion_tess.iter() {
+                shd_gate.shade(&programs.onion, |iface, mut rdr_gate| {
+                    let uniform = iface.query();
+                    uniform.ask("tex").unwrap().update(&drawing_buffer);
+                    uniform.ask("view").unwrap().update(onion_view);
+                    uniform.ask("tint").unwrap().update(*tint);
+
+                    rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(onion_tess) );
+                });
+            }
+
             // render canvas
-            shd_gate.shade(&program, |iface, mut rdr_gate| {
+            shd_gate.shade(&programs.canvas, |iface, mut rdr_gate| {
                 iface.query().ask("tex").unwrap().update(&drawing_buffer);
                 iface.query().ask("view").unwrap().update(canvas_view);
 
-                rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(&tess) );
+                rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(canvas_tess) );
             });
 
+            // render grid
+            if let Some(grid_tess) = grid_tess.as_ref() {
+                shd_gate.shade(&programs.grid, |iface, mut rdr_gate| {
+                    iface.query().ask("view").unwrap().update(canvas_view);
+
+                    rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(grid_tess) );
+                });
+            }
+
+            // render guides, over the grid but under the selection
+            if let Some(guide_tess) = guide_tess.as_ref() {
+                shd_gate.shade(&programs.grid, |iface, mut rdr_gate| {
+                    iface.query().ask("view").unwrap().update(canvas_view);
+
+                    rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(guide_tess) );
+                });
+            }
+
             // render selector
-            shd_gate.shade(&select_program, |iface, mut rdr_gate| {
+            shd_gate.shade(&programs.selection, |iface, mut rdr_gate| {
                 iface.query().ask("tex").unwrap().update(&select_atlas);
                 iface.query().ask("view").unwrap().update(canvas_view);
 
-                rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(&select_tess) );
+                rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(select_tess) );
+            });
+
+            // render cursor
+            if let Some(cursor_tess) = cursor_tess.as_ref() {
+                shd_gate.shade(&programs.cursor, |iface, mut rdr_gate| {
+                    iface.query().ask("view").unwrap().update(canvas_view);
+
+                    rdr_gate.render(&cursor_render_state, |mut tess_gate| {
+                        tess_gate.render(cursor_tess);
+                    });
+                });
+            }
+        });
+
+        // the other pane only shows its canvas
+        if let Some(split) = state.split {
+            let other_x = if split.focus_left { pane_size.0 } else { 0.0 };
+            let pipestate = PipelineState::new()
+                .enable_clear_color(false)
+                .set_viewport(pane_viewport(other_x));
+            let (other_tex, other_tess) = match split_canvas.as_ref() {
+                Some((_, split_tex, split_tess)) => (split_tex, split_tess),
+                None => (&tex, &tess),
+            };
+
+            glfw.pipeline_builder().pipeline(&framebuffer, &pipestate, |pipeline, mut shd_gate| {
+                let drawing_buffer = pipeline.bind_texture(other_tex);
+                let view = to_raw(view_matrix(split.zoom, split.center, pane_size));
+
+                if state.checker.enabled {
+                    shd_gate.shade(&programs.checker, |iface, mut rdr_gate| {
+                        let uniform = iface.query();
+                        uniform.ask("view").unwrap().update(view);
+                        uniform.ask("size").unwrap().update(state.checker.size);
+                        uniform.ask("light").unwrap().update(state.checker.light);
+                        uniform.ask("dark").unwrap().update(state.checker.dark);
+
+                        rdr_gate.render(&render_state, |mut tess_gate| {
+                            tess_gate.render(other_tess);
+                        });
+                    });
+                }
+
+                shd_gate.shade(&programs.canvas, |iface, mut rdr_gate| {
+                    iface.query().ask("tex").unwrap().update(&drawing_buffer);
+                    iface.query().ask("view").unwrap().update(view);
+
+                    rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(other_tess) );
+                });
             });
+        }
+
+        let pipestate = PipelineState::new().enable_clear_color(false);
+
+        glfw.pipeline_builder().pipeline(&framebuffer, &pipestate, |pipeline, mut shd_gate| {
+            let drawing_buffer = pipeline.bind_texture(&tex);
+            let font_atlas = pipeline.bind_texture(&text.atlas);
+
+            let text_view = {
+                let center_x = (state.window_size.0) / 2.0;
+                let center_y = (state.window_size.1) / 2.0;
+
+                to_raw(scale(state.scale.0, -state.scale.1) * translate(-center_x, -center_y))
+            };
+
+            // the frame preview is in the top-right corner of the window
+            let preview_view = {
+                let (w, _) = state.canvas.size();
+                let frame_width = (w / state.frames.max(1)) as f32 * PREVIEW_PIXEL_SIZE;
+                let x = state.window_size.0 - PREVIEW_MARGIN - frame_width;
+
+                to_raw(translate(-1.0, 1.0) * scale(2.0 * state.scale.0, -2.0 * state.scale.1)
+                    * translate(x, PREVIEW_MARGIN) * scale(PREVIEW_PIXEL_SIZE, PREVIEW_PIXEL_SIZE))
+            };
+
+            // render minimap
+            if let Some(k) = minimap_scale(&state) {
+                let minimap_view = to_raw(translate(-1.0, 1.0)
+                    * scale(2.0 * state.scale.0, -2.0 * state.scale.1)
+                    * translate(MINIMAP_MARGIN, MINIMAP_MARGIN) * scale(k, k));
+
+                shd_gate.shade(&programs.canvas, |iface, mut rdr_gate| {
+                    iface.query().ask("tex").unwrap().update(&drawing_buffer);
+                    iface.query().ask("view").unwrap().update(minimap_view);
+
+                    rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(&tess) );
+                });
+
+                if let Some(minimap_tess) = minimap_tess.as_ref() {
+                    shd_gate.shade(&programs.grid, |iface, mut rdr_gate| {
+                        iface.query().ask("view").unwrap().update(minimap_view);
+
+                        rdr_gate.render(&render_state, |mut tess_gate| {
+                            tess_gate.render(minimap_tess);
+                        });
+                    });
+                }
+            }
+
+            // render frame preview
+            if let Some(preview_tess) = preview_tess.as_ref() {
+                shd_gate.shade(&programs.canvas, |iface, mut rdr_gate| {
+                    iface.query().ask("tex").unwrap().update(&drawing_buffer);
+                    iface.query().ask("view").unwrap().update(preview_view);
+
+                    rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(preview_tess) );
+                });
+            }
+
+            // render palette swatches, under their keys
+            if let Some(swatch_tess) = swatch_tess.as_ref() {
+                shd_gate.shade(&programs.swatch, |iface, mut rdr_gate| {
+                    iface.query().ask("view").unwrap().update(text_view);
+
+                    rdr_gate.render(&render_state, |mut tess_gate| tess_gate.render(swatch_tess) );
+                });
+            }
 
             // render ui text
             text_tess.map(|text_tess| {
-                shd_gate.shade(&text_program, |iface, mut rdr_gate| {
+                shd_gate.shade(&programs.text, |iface, mut rdr_gate| {
                     let uniform = iface.query();
                     uniform.ask("tex").unwrap().update(&font_atlas);
                     uniform.ask("view").unwrap().update(text_view);
@@ -436,5 +3238,13 @@ fn main() {
 
         // display
         glfw.swap_buffers();
+
+        wait_for_next_frame(state.fps, now);
+    }
+
+    // a session ending with the canvas written leaves no swap file behind, the swap file keeping
+    // the changes which would be lost otherwise
+    if !state.canvas.is_modified() {
+        let _ = fs::remove_file(&swap_file);
     }
 }
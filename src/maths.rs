@@ -1,4 +1,4 @@
-use glm::{Mat3, mat3, GenMat};
+use glm::{Mat3, mat3, vec3, GenMat, GenSquareMat};
 
 /// Builds a translation matrix `glm` matrix.
 pub fn translate(x:f32, y:f32) -> Mat3 {
@@ -18,6 +18,21 @@ pub fn scale(sx:f32, sy:f32) -> Mat3 {
     )
 }
 
+/// Builds a `glm` matrix rotating by `theta` radians around the origin.
+pub fn rotate(theta:f32) -> Mat3 {
+    let (sin, cos) = theta.sin_cos();
+    mat3(
+         cos, sin, 0.0,
+        -sin, cos, 0.0,
+         0.0, 0.0, 1.0
+    )
+}
+
+/// Builds a `glm` matrix rotating by `theta` radians around the point `(cx, cy)`.
+pub fn rotate_around(cx:f32, cy:f32, theta:f32) -> Mat3 {
+    translate(cx, cy) * rotate(theta) * translate(-cx, -cy)
+}
+
 /// Type-coercion for `glm` to `luminance` matrix representation.
 pub fn to_raw(mut mat:Mat3) -> [[f32; 3]; 3] {
     mat = mat.transpose();
@@ -25,3 +40,62 @@ pub fn to_raw(mut mat:Mat3) -> [[f32; 3]; 3] {
         *((&mat as * const Mat3) as * const [[f32; 3]; 3]).as_ref().unwrap()
     }
 }
+
+/// Inverse of a matrix, if it is invertible.
+pub fn inverse(mat:Mat3) -> Option<Mat3> {
+    mat.inverse()
+}
+
+/// Apply a matrix to a 2D point.
+pub fn transform_point(mat:Mat3, (x, y):(f32, f32)) -> (f32, f32) {
+    let v = mat * vec3(x, y, 1.0);
+    (v.x, v.y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glm::ApproxEq;
+    use std::f32::consts::{PI, FRAC_PI_2};
+
+    const EPSILON : f32 = 1e-5;
+
+    fn identity() -> Mat3 {
+        scale(1.0, 1.0)
+    }
+
+    #[test]
+    fn inverse_undoes_scale_and_translate() {
+        let mat = scale(2.0, -0.5) * translate(3.0, -4.0);
+        let inv = inverse(mat).unwrap();
+
+        assert!((inv * mat).is_close_to(&identity(), EPSILON));
+        assert!((mat * inv).is_close_to(&identity(), EPSILON));
+    }
+
+    #[test]
+    fn singular_matrix_has_no_inverse() {
+        assert!(inverse(scale(0.0, 1.0)).is_none());
+    }
+
+    #[test]
+    fn transform_point_applies_translate_then_scale() {
+        let (x, y) = transform_point(scale(2.0, 3.0) * translate(1.0, -1.0), (1.0, 2.0));
+        assert!((x - 4.0).abs() < EPSILON && (y - 3.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn full_turn_is_identity() {
+        assert!(rotate(2.0 * PI).is_close_to(&identity(), EPSILON));
+        assert!(rotate_around(3.0, -2.0, 2.0 * PI).is_close_to(&identity(), EPSILON));
+    }
+
+    #[test]
+    fn quarter_turn_is_counterclockwise_around_center() {
+        let (x, y) = transform_point(rotate(FRAC_PI_2), (1.0, 0.0));
+        assert!(x.abs() < EPSILON && (y - 1.0).abs() < EPSILON);
+
+        let (x, y) = transform_point(rotate_around(1.0, 1.0, FRAC_PI_2), (2.0, 1.0));
+        assert!((x - 1.0).abs() < EPSILON && (y - 2.0).abs() < EPSILON);
+    }
+}
@@ -0,0 +1,162 @@
+use std::{fmt, fs, io, collections::HashMap};
+use crate::keyboard::CharKeyMod;
+
+/// Keys the colors of a loaded palette are bound to, in order.
+pub const PALETTE_KEYS : &[&str] = &[
+    "a", "z", "e", "r", "t", "y", "u", "i", "o", "p",
+    "q", "s", "d", "f", "g", "h", "j", "k", "l", "m",
+    "w", "x", "c", "v", "b", "n",
+];
+
+/// Colors bound to keys, remembered in the order they were first bound so that they can be
+/// cycled through.
+#[derive(Default)]
+pub struct Palette {
+    colors: HashMap<CharKeyMod, (u8, u8, u8)>,
+    order: Vec<CharKeyMod>,
+}
+
+impl Palette {
+    /// Bind a color to a key, replacing and returning the color it was bound to if any.
+    pub fn insert(&mut self, key:CharKeyMod, color:(u8, u8, u8)) -> Option<(u8, u8, u8)> {
+        let old = self.colors.insert(key, color);
+        if old.is_none() {
+            self.order.push(key);
+        }
+        old
+    }
+
+    pub fn get(&self, key:&CharKeyMod) -> Option<&(u8, u8, u8)> {
+        self.colors.get(key)
+    }
+
+    pub fn clear(&mut self) {
+        self.colors.clear();
+        self.order.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Return the colors, in binding order.
+    pub fn colors(&self) -> Vec<(u8, u8, u8)> {
+        self.order.iter().map(|key| self.colors[key]).collect()
+    }
+
+    /// Return the position of the entry of a key, in binding order.
+    pub fn position(&self, key:&CharKeyMod) -> Option<usize> {
+        self.order.iter().position(|k| k == key)
+    }
+
+    /// Return the key and the color of the given entry, in binding order.
+    pub fn entry(&self, i:usize) -> Option<(CharKeyMod, (u8, u8, u8))> {
+        let key = *self.order.get(i)?;
+        Some((key, self.colors[&key]))
+    }
+}
+
+/// Put a color at the front of a list of recently used colors, moving it there if it is already
+/// in the list, and drop the oldest colors beyond the given length.
+pub fn push_recent(recent:&mut Vec<(u8, u8, u8)>, color:(u8, u8, u8), max:usize) {
+    recent.retain(|&c| c != color);
+    recent.insert(0, color);
+    recent.truncate(max);
+}
+
+/// Colors that were made the current color, to step back and forth through them. Stepping does
+/// not record anything, and a color made current after stepping back replaces the later ones.
+#[derive(Default)]
+pub struct ColorHistory {
+    colors: Vec<(u8, u8, u8)>,
+    index: usize,
+}
+
+impl ColorHistory {
+    /// Record a color made current, unless it is the one the history is at. The oldest colors
+    /// beyond the given length are dropped.
+    pub fn record(&mut self, color:(u8, u8, u8), max:usize) {
+        if self.colors.get(self.index) == Some(&color) {
+            return
+        }
+
+        self.colors.truncate(self.index + 1);
+        self.colors.push(color);
+        if self.colors.len() > max {
+            self.colors.remove(0);
+        }
+        self.index = self.colors.len() - 1;
+    }
+
+    /// Step to the previous color if `back` is set, else to the next one, and return it.
+    pub fn step(&mut self, back:bool) -> Option<(u8, u8, u8)> {
+        let index = if back { self.index.checked_sub(1)? } else { self.index + 1 };
+        let color = *self.colors.get(index)?;
+        self.index = index;
+        Some(color)
+    }
+
+    /// Position of the history, from 1, and number of colors in it.
+    pub fn position(&self) -> (usize, usize) {
+        (self.index + 1, self.colors.len())
+    }
+}
+
+#[derive(Debug)]
+pub enum PaletteError {
+    Io(io::Error),
+    MissingHeader,
+    InvalidRow(usize),
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaletteError::Io(e) => write!(f, "{}", e),
+            PaletteError::MissingHeader => write!(f, "not a GIMP palette"),
+            PaletteError::InvalidRow(line) => write!(f, "invalid color at line {}", line),
+        }
+    }
+}
+
+impl From<io::Error> for PaletteError {
+    fn from(e:io::Error) -> Self {
+        PaletteError::Io(e)
+    }
+}
+
+/// Read the colors of a GIMP palette (`.gpl`) file, with their names.
+pub fn load_gpl(path:&str) -> Result<Vec<(u8, u8, u8, String)>, PaletteError> {
+    let content = fs::read_to_string(path)?;
+    let mut lines = content.lines().enumerate();
+
+    match lines.next() {
+        Some((_, header)) if header.trim() == "GIMP Palette" => {},
+        _ => return Err(PaletteError::MissingHeader),
+    }
+
+    let mut colors = Vec::new();
+    for (n, line) in lines {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#')
+            || line.starts_with("Name:") || line.starts_with("Columns:") {
+            continue
+        }
+
+        let mut words = line.split_whitespace();
+        let mut channel = || words.next().and_then(|w| w.parse::<u8>().ok());
+        match (channel(), channel(), channel()) {
+            (Some(r), Some(g), Some(b)) => {
+                let name = words.collect::<Vec<_>>().join(" ");
+                colors.push((r, g, b, name));
+            },
+            _ => return Err(PaletteError::InvalidRow(n + 1)),
+        }
+    }
+
+    Ok(colors)
+}
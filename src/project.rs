@@ -0,0 +1,262 @@
+use std::{fmt, io::{self, Read, Write}, collections::HashSet};
+use serde::{Serialize, Deserialize};
+use crate::canvas::{Canvas, Layer, Pixel};
+use crate::keyboard::{CharKeyMod, KeyParseError};
+
+/// First bytes of a project file.
+const MAGIC : &[u8; 4] = b"VIP1";
+
+/// What is saved in a project file along with the canvas: the number of frames, the palette as
+/// its keys and colors in binding order, the current color and the selection.
+pub struct Project {
+    pub frames: usize,
+    pub palette: Vec<(CharKeyMod, (u8, u8, u8))>,
+    pub color: (u8, u8, u8),
+    pub selection: HashSet<(usize, usize)>,
+}
+
+/// Description of a project written as JSON at the start of the file, the pixels of the layers
+/// following it. The keys of the palette are written as they are typed, such as `<C-x>`.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    size: (usize, usize),
+    layers: Vec<LayerInfo>,
+    active_layer: usize,
+    frames: usize,
+    palette: Vec<(String, (u8, u8, u8))>,
+    color: (u8, u8, u8),
+    selection: Vec<(usize, usize)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LayerInfo {
+    name: String,
+    visible: bool,
+    opacity: f32,
+}
+
+#[derive(Debug)]
+pub enum ProjectError {
+    Io(io::Error),
+    NotAProject,
+    InvalidManifest(serde_json::Error),
+    InvalidKey(KeyParseError),
+}
+
+impl fmt::Display for ProjectError {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProjectError::Io(e) => write!(f, "{}", e),
+            ProjectError::NotAProject => write!(f, "not a vip project"),
+            ProjectError::InvalidManifest(e) => write!(f, "invalid description: {}", e),
+            ProjectError::InvalidKey(e) => write!(f, "invalid palette key: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for ProjectError {
+    fn from(e:io::Error) -> Self {
+        ProjectError::Io(e)
+    }
+}
+
+/// Write a canvas and its project: the magic bytes, the length of the manifest as 4 little-endian
+/// bytes, the manifest, then the RGBA bytes of every layer from the bottom one to the top one.
+pub fn write<W:Write>(mut out:W, canvas:&Canvas, project:&Project) -> Result<(), ProjectError> {
+    let manifest = Manifest {
+        size: canvas.size(),
+        layers: canvas.layers()
+            .iter()
+            .map(|l| LayerInfo { name: l.name.clone(), visible: l.visible, opacity: l.opacity })
+            .collect(),
+        active_layer: canvas.active_layer(),
+        frames: project.frames,
+        palette: project.palette.iter().map(|(key, color)| (key.to_string(), *color)).collect(),
+        color: project.color,
+        selection: project.selection.iter().cloned().collect(),
+    };
+    let manifest = serde_json::to_vec(&manifest).map_err(ProjectError::InvalidManifest)?;
+
+    out.write_all(MAGIC)?;
+    out.write_all(&(manifest.len() as u32).to_le_bytes())?;
+    out.write_all(&manifest)?;
+    for layer in canvas.layers() {
+        let bytes : Vec<u8> = layer.pixels()
+            .iter()
+            .flat_map(|&(r, g, b, a)| vec![r, g, b, a])
+            .collect();
+        out.write_all(&bytes)?;
+    }
+    Ok(())
+}
+
+/// Read the given number of bytes. The buffer grows as the bytes are read rather than being
+/// allocated at once, so that a damaged file announcing a huge length fails to be read instead.
+fn read_bytes<R:Read>(input:R, length:usize) -> Result<Vec<u8>, ProjectError> {
+    let mut bytes = Vec::new();
+    input.take(length as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < length {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+    }
+    Ok(bytes)
+}
+
+/// Read a canvas and its project written by `write`.
+pub fn read<R:Read>(mut input:R) -> Result<(Canvas, Project), ProjectError> {
+    let mut magic = [0; 4];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(ProjectError::NotAProject)
+    }
+
+    let mut length = [0; 4];
+    input.read_exact(&mut length)?;
+    let manifest = read_bytes(&mut input, u32::from_le_bytes(length) as usize)?;
+    let manifest : Manifest = serde_json::from_slice(&manifest)
+        .map_err(ProjectError::InvalidManifest)?;
+
+    let (w, h) = manifest.size;
+    let layer_length = match w.checked_mul(h).and_then(|n| n.checked_mul(4)) {
+        Some(n) if n > 0 && !manifest.layers.is_empty() => n,
+        _ => return Err(ProjectError::NotAProject),
+    };
+
+    let mut layers = Vec::new();
+    for info in manifest.layers {
+        let bytes = read_bytes(&mut input, layer_length)?;
+        let pixels : Vec<Pixel> = bytes.chunks(4).map(|c| (c[0], c[1], c[2], c[3])).collect();
+
+        let mut layer = Layer::from_pixels(info.name, pixels);
+        layer.visible = info.visible;
+        layer.opacity = info.opacity.max(0.0).min(1.0);
+        layers.push(layer);
+    }
+
+    let mut canvas = Canvas::from_layers((w, h), layers);
+    canvas.set_active_layer(manifest.active_layer);
+
+    let palette = manifest.palette
+        .iter()
+        .map(|(key, color)| CharKeyMod::try_from(key).map(|key| (key, *color)))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(ProjectError::InvalidKey)?;
+
+    let project = Project {
+        frames: manifest.frames.max(1).min(w),
+        palette,
+        color: manifest.color,
+        selection: manifest.selection.into_iter().filter(|&(x, y)| x < w && y < h).collect(),
+    };
+    Ok((canvas, project))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 2x2 canvas with an opaque layer under a hidden and half transparent one, and a project
+    /// using every field.
+    fn sample() -> (Canvas, Project) {
+        let background = Layer::from_pixels("Background", vec![(10, 20, 30, 255); 4]);
+        let mut top = Layer::from_pixels("Top", vec![
+            (255, 0, 0, 255), (0, 0, 0, 0), (0, 0, 0, 0), (0, 255, 0, 128),
+        ]);
+        top.visible = false;
+        top.opacity = 0.5;
+
+        let mut canvas = Canvas::from_layers((2, 2), vec![background, top]);
+        canvas.set_active_layer(1);
+
+        let project = Project {
+            frames: 2,
+            palette: vec![
+                (CharKeyMod::from("a"), (1, 2, 3)),
+                (CharKeyMod::from("<C-x>"), (4, 5, 6)),
+                (CharKeyMod::from("<Space>"), (7, 8, 9)),
+            ],
+            color: (4, 5, 6),
+            selection: [(0, 0), (1, 1)].iter().cloned().collect(),
+        };
+        (canvas, project)
+    }
+
+    fn written(canvas:&Canvas, project:&Project) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write(&mut bytes, canvas, project).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trip_keeps_the_layers() {
+        let (canvas, project) = sample();
+        let (read_canvas, _) = read(&written(&canvas, &project)[..]).unwrap();
+
+        assert_eq!(read_canvas.size(), (2, 2));
+        assert_eq!(read_canvas.active_layer(), 1);
+        assert_eq!(read_canvas.layers().len(), 2);
+        for (read_layer, layer) in read_canvas.layers().iter().zip(canvas.layers()) {
+            assert_eq!(read_layer.name, layer.name);
+            assert_eq!(read_layer.visible, layer.visible);
+            assert_eq!(read_layer.opacity, layer.opacity);
+            assert_eq!(read_layer.pixels(), layer.pixels());
+        }
+    }
+
+    #[test]
+    fn round_trip_keeps_the_project() {
+        let (canvas, project) = sample();
+        let (_, read_project) = read(&written(&canvas, &project)[..]).unwrap();
+
+        assert_eq!(read_project.frames, project.frames);
+        assert_eq!(read_project.palette, project.palette);
+        assert_eq!(read_project.color, project.color);
+        assert_eq!(read_project.selection, project.selection);
+    }
+
+    #[test]
+    fn bad_magic_is_not_a_project() {
+        let (canvas, project) = sample();
+        let mut bytes = written(&canvas, &project);
+        bytes[0] = b'X';
+
+        match read(&bytes[..]) {
+            Err(ProjectError::NotAProject) => {},
+            _ => panic!("a file with another magic was read as a project"),
+        }
+    }
+
+    #[test]
+    fn truncated_pixels_fail_to_be_read() {
+        let (canvas, project) = sample();
+        let mut bytes = written(&canvas, &project);
+        bytes.pop();
+
+        match read(&bytes[..]) {
+            Err(ProjectError::Io(_)) => {},
+            _ => panic!("a truncated file was read"),
+        }
+    }
+
+    #[test]
+    fn overflowing_size_is_not_a_project() {
+        let manifest = Manifest {
+            size: (usize::MAX, 2),
+            layers: vec![LayerInfo { name: "Layer 1".to_string(), visible: true, opacity: 1.0 }],
+            active_layer: 0,
+            frames: 1,
+            palette: Vec::new(),
+            color: (0, 0, 0),
+            selection: Vec::new(),
+        };
+        let manifest = serde_json::to_vec(&manifest).unwrap();
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&(manifest.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&manifest);
+
+        match read(&bytes[..]) {
+            Err(ProjectError::NotAProject) => {},
+            _ => panic!("a size overflowing the memory was accepted"),
+        }
+    }
+}
@@ -0,0 +1,56 @@
+/// Rasterize the segment between two points with Bresenham's algorithm. Both ends are included and
+/// consecutive points are 8-connected. Points with negative coordinates are dropped.
+pub fn line(x0:isize, y0:isize, x1:isize, y1:isize) -> Vec<(usize, usize)> {
+    let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+    let (sx, sy) = ((x1 - x0).signum(), (y1 - y0).signum());
+    let mut err = dx + dy;
+
+    let (mut x, mut y) = (x0, y0);
+    let mut ret = Vec::new();
+
+    loop {
+        if x >= 0 && y >= 0 {
+            ret.push((x as usize, y as usize));
+        }
+
+        if x == x1 && y == y1 {
+            break
+        }
+
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y += sy;
+        }
+    }
+
+    ret
+}
+
+/// Return the cells of the rectangle between two corners, both included.
+pub fn rectangle(x1:usize, y1:usize, x2:usize, y2:usize) -> Vec<(usize, usize)> {
+    (y1..y2 + 1).flat_map(|y| (x1..x2 + 1).map(move |x| (x, y))).collect()
+}
+
+/// Return the cells covered by a brush of the given size centered on a cell, either a square or a
+/// disc. Cells with negative coordinates are dropped.
+pub fn brush_cells((cx, cy):(usize, usize), size:usize, round:bool) -> Vec<(usize, usize)> {
+    let size = size.max(1) as isize;
+    let (x0, y0) = (cx as isize - (size - 1) / 2, cy as isize - (size - 1) / 2);
+    let radius = size as f32 / 2.0;
+
+    (y0..y0 + size)
+        .flat_map(|y| (x0..x0 + size).map(move |x| (x, y)))
+        .filter(|&(x, y)| {
+            let dx = (x - x0) as f32 + 0.5 - radius;
+            let dy = (y - y0) as f32 + 0.5 - radius;
+            !round || dx * dx + dy * dy <= radius * radius
+        })
+        .filter(|&(x, y)| x >= 0 && y >= 0)
+        .map(|(x, y)| (x as usize, y as usize))
+        .collect()
+}
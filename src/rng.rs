@@ -0,0 +1,67 @@
+/// A small pseudo-random number generator (xorshift64*), giving the same numbers for the same
+/// seed so that random effects can be reproduced.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed:u64) -> Self {
+        // the state must never be zero
+        Self { state: (seed ^ 0x9E37_79B9_7F4A_7C15) | 1 }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Return a number in `[min, max]`.
+    pub fn range(&mut self, min:i32, max:i32) -> i32 {
+        if max <= min {
+            return min
+        }
+
+        let span = (max as i64 - min as i64 + 1) as u64;
+        (min as i64 + (self.next_u64() % span) as i64) as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pinned_seed_gives_pinned_numbers() {
+        let mut rng = Rng::new(42);
+        assert_eq!(rng.next_u64(), 0x0832_8D7F_03BC_EC1A);
+        assert_eq!(rng.next_u64(), 0x077E_7279_E17A_B6CD);
+        assert_eq!(rng.next_u64(), 0x0C4E_098F_541B_B09E);
+
+        let mut rng = Rng::new(42);
+        let numbers : Vec<i32> = (0..8).map(|_| rng.range(-3, 3)).collect();
+        assert_eq!(numbers, vec![-1, -3, -2, 0, 3, -1, 2, 0]);
+    }
+
+    #[test]
+    fn same_seed_gives_same_numbers() {
+        let (mut a, mut b) = (Rng::new(7), Rng::new(7));
+        for _ in 0..100 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+        assert_ne!(Rng::new(7).next_u64(), Rng::new(8).next_u64());
+    }
+
+    #[test]
+    fn range_stays_within_bounds() {
+        let mut rng = Rng::new(0);
+        for _ in 0..1000 {
+            let n = rng.range(-5, 5);
+            assert!(n >= -5 && n <= 5);
+        }
+        assert_eq!(rng.range(3, 3), 3);
+        assert_eq!(rng.range(3, -3), 3);
+    }
+}
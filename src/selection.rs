@@ -8,6 +8,11 @@ use luminance::{
     linear::M33,
 };
 use std::collections::HashSet;
+use std::iter::FromIterator;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use image::{RgbaImage, Rgba};
+use crate::bitmap2d::BitMap2D;
 use crate::canvas::Canvas;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Semantics)]
@@ -38,10 +43,179 @@ pub struct ShaderInterface {
     view: Uniform<M33>,
 }
 
-pub fn vertice_from_selection(selection:&HashSet<(usize,usize)>, canvas:&Canvas) -> Vec<Vertex> {
+/// Size of the atlas of the selection borders, in pixels. The atlas holds 4x4 cells, a cell for
+/// every combination of borders around a selected pixel: the column tells whether the left (1)
+/// and the right (2) borders are drawn, the row whether the bottom (1) and the top (2) are.
+pub const ATLAS_SIZE : u32 = 256;
+
+/// Build an atlas of the selection borders drawing them as white lines over a faint white tint,
+/// used when no atlas image can be loaded.
+pub fn generated_atlas() -> RgbaImage {
+    let cell = ATLAS_SIZE / 4;
+    let width = cell / 8;
+
+    RgbaImage::from_fn(ATLAS_SIZE, ATLAS_SIZE, |x, y| {
+        let (column, row) = (x / cell, y / cell);
+        let (cx, cy) = (x % cell, y % cell);
+
+        let border = (column & 1 != 0 && cx < width)
+            || (column & 2 != 0 && cx >= cell - width)
+            || (row & 1 != 0 && cy >= cell - width)
+            || (row & 2 != 0 && cy < width);
+
+        if border { Rgba([255, 255, 255, 255]) } else { Rgba([255, 255, 255, 64]) }
+    })
+}
+
+/// Source of the generations of the selections.
+static GENERATIONS: AtomicU64 = AtomicU64::new(0);
+
+fn next_generation() -> u64 {
+    GENERATIONS.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Positions of selected pixels, with a generation: a number no other state of any selection had,
+/// given anew whenever the positions are borrowed mutably. While it stays the same, so do the
+/// positions.
+pub struct Selection {
+    set: HashSet<(usize, usize)>,
+    generation: u64,
+}
+
+impl Selection {
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+impl Default for Selection {
+    fn default() -> Self {
+        HashSet::new().into()
+    }
+}
+
+impl From<HashSet<(usize, usize)>> for Selection {
+    fn from(set:HashSet<(usize, usize)>) -> Self {
+        Self { set, generation: next_generation() }
+    }
+}
+
+impl FromIterator<(usize, usize)> for Selection {
+    fn from_iter<I:IntoIterator<Item = (usize, usize)>>(iter:I) -> Self {
+        iter.into_iter().collect::<HashSet<_>>().into()
+    }
+}
+
+impl Deref for Selection {
+    type Target = HashSet<(usize, usize)>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.set
+    }
+}
+
+impl DerefMut for Selection {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.generation = next_generation();
+        &mut self.set
+    }
+}
+
+/// Return the smallest rectangle containing the selection, as its top-left corner and its size.
+pub fn bounding_box(selection:&HashSet<(usize, usize)>) -> Option<((usize, usize), (usize, usize))> {
+    let x1 = selection.iter().map(|&(x, _)| x).min()?;
+    let y1 = selection.iter().map(|&(_, y)| y).min()?;
+    let x2 = selection.iter().map(|&(x, _)| x).max()?;
+    let y2 = selection.iter().map(|&(_, y)| y).max()?;
+    Some(((x1, y1), (x2 - x1 + 1, y2 - y1 + 1)))
+}
+
+/// Return the cells of a canvas of the given size next to a cell: the ones sharing a side with
+/// it, or also the ones sharing a corner with it if `diagonal` is set.
+fn neighbours((x, y):(usize, usize), w:usize, h:usize, diagonal:bool)
+    -> impl Iterator<Item=(usize, usize)> {
+    let offsets : &'static [(isize, isize)] = if diagonal {
+        &[(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)]
+    } else {
+        &[(0, -1), (-1, 0), (1, 0), (0, 1)]
+    };
+
+    offsets
+        .iter()
+        .map(move |&(dx, dy)| (x as isize + dx, y as isize + dy))
+        .filter(move |&(x, y)| x >= 0 && y >= 0 && (x as usize) < w && (y as usize) < h)
+        .map(|(x, y)| (x as usize, y as usize))
+}
+
+/// Return the cells of a canvas of the given size which are next to a selected cell but are not
+/// selected themselves. Only the cells sharing a side with a selected one count, unless
+/// `diagonal` is set, in which case the ones sharing a corner do too.
+pub fn outline_of(set:&HashSet<(usize, usize)>, w:usize, h:usize, diagonal:bool)
+    -> HashSet<(usize, usize)> {
+    set.iter()
+        .flat_map(|&pos| neighbours(pos, w, h, diagonal))
+        .filter(|pos| !set.contains(pos))
+        .collect()
+}
+
+/// Add to the selection the cells at most `n` steps away from it, a step going to a neighbouring
+/// cell as in `outline_of`. Only the cells added by the previous step are looked around.
+pub fn grow(set:&mut HashSet<(usize, usize)>, (w, h):(usize, usize), n:usize, diagonal:bool) {
+    let mut front : Vec<(usize, usize)> = set.iter().cloned().collect();
+    for _ in 0..n {
+        let mut added = Vec::new();
+        for &pos in &front {
+            for neighbour in neighbours(pos, w, h, diagonal) {
+                if set.insert(neighbour) {
+                    added.push(neighbour);
+                }
+            }
+        }
+
+        if added.is_empty() {
+            break
+        }
+        front = added;
+    }
+}
+
+/// Remove from the selection the cells at most `n` steps away from an unselected cell, the cells
+/// out of the canvas counting as unselected. Only the cells next to the ones removed by the
+/// previous step are looked at.
+pub fn shrink(set:&mut HashSet<(usize, usize)>, (w, h):(usize, usize), n:usize, diagonal:bool) {
+    let neighbour_count = if diagonal { 8 } else { 4 };
+    let on_border = |set:&HashSet<(usize, usize)>, pos| {
+        let selected = neighbours(pos, w, h, diagonal).filter(|pos| set.contains(pos)).count();
+        selected < neighbour_count
+    };
+
+    let mut candidates : HashSet<(usize, usize)> = set.iter().cloned().collect();
+    for _ in 0..n {
+        let removed : Vec<(usize, usize)> = candidates
+            .iter()
+            .cloned()
+            .filter(|&pos| set.contains(&pos) && on_border(set, pos))
+            .collect();
+
+        if removed.is_empty() {
+            break
+        }
+
+        for pos in &removed {
+            set.remove(pos);
+        }
+        candidates = removed
+            .iter()
+            .flat_map(|&pos| neighbours(pos, w, h, diagonal))
+            .filter(|pos| set.contains(pos))
+            .collect();
+    }
+}
+
+pub fn vertice_from_selection<B:BitMap2D>(selection:&B, canvas:&Canvas) -> Vec<Vertex> {
     let mut ret = Vec::new();
-    for (x, y) in selection {
-        let (ix, iy) = (*x as isize, *y as isize);
+    for (x, y) in selection.iter_set() {
+        let (ix, iy) = (x as isize, y as isize);
 
         let (tx, ty) = 
         [   ( 0, -1, (0, 2)),
@@ -55,7 +229,7 @@ pub fn vertice_from_selection(selection:&HashSet<(usize,usize)>, canvas:&Canvas)
                 .iter()
                 .map(|(dx, dy, weight)| {
                     let pt = (ix.wrapping_add(*dx) as usize, iy.wrapping_add(*dy) as usize);
-                    if !selection.contains(&pt) {
+                    if !selection.get_bit(pt.0, pt.1) {
                         *weight
                     } else {
                         (0, 0)
@@ -63,16 +237,16 @@ pub fn vertice_from_selection(selection:&HashSet<(usize,usize)>, canvas:&Canvas)
                 })
                 .fold((0, 0), |(x1,y1), (x2,y2)| (x1+x2, y1+y2));
 
-        let cs = 64.0;
-        let ats = 256.0;
+        let ats = ATLAS_SIZE as f32;
+        let cs = ats / 4.0;
 
         let ts = cs / ats;
         let (tx, ty) = (cs*(tx as f32 )/ ats, cs*(ty as f32) / ats);
 
-        let (r, g, b) = canvas.get_pixel_color(*x, *y);
+        let (r, g, b) = canvas.get_composite_color(x, y);
         let scol = [r, g, b];
 
-        let (px, py) = (*x as f32, *y as f32);
+        let (px, py) = (x as f32, y as f32);
         ret.extend_from_slice(&[
             Vertex {
                 pos: SelPos::new([px, py]),
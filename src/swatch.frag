@@ -0,0 +1,8 @@
+in vec4 fcolor;
+
+out vec4 diffuseColor;
+
+void main()
+{
+    diffuseColor = fcolor;
+}
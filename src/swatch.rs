@@ -0,0 +1,53 @@
+use luminance_derive::{Semantics, Vertex};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Semantics)]
+pub enum Semantics {
+    #[sem(name="pos", repr="[f32;2]", wrapper="SwatchPos")]
+    Position,
+    #[sem(name="color", repr="[u8;4]", wrapper="SwatchColor")]
+    Color,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Vertex)]
+#[vertex(sem = "Semantics")]
+pub struct Vertex {
+    pos: SwatchPos,
+    #[vertex(normalized="true")]
+    color: SwatchColor,
+}
+
+/// Size of the side of a swatch, and space between two swatches, in screen pixels.
+pub const SIZE : f32 = 24.0;
+pub const SPACING : f32 = 4.0;
+
+/// Width of the frame drawn around the swatch of the current color, in screen pixels.
+const FRAME : f32 = 2.0;
+
+/// Return the top-left corner of each of `count` swatches in a row starting at the given position
+/// and going right.
+pub fn positions(count:usize, (x, y):(f32, f32)) -> Vec<(f32, f32)> {
+    (0..count).map(|i| (x + i as f32 * (SIZE + SPACING), y)).collect()
+}
+
+fn quad((x1, y1):(f32, f32), (x2, y2):(f32, f32), color:[u8; 4]) -> Vec<Vertex> {
+    [[x1, y1], [x2, y1], [x1, y2], [x1, y2], [x2, y2], [x2, y1]]
+        .iter()
+        .map(|&pos| Vertex { pos: SwatchPos::new(pos), color: SwatchColor::new(color) })
+        .collect()
+}
+
+/// Build the swatches of the given colors at the given positions, to be drawn with
+/// `Mode::Triangle` in screen pixels. The swatches of the current color are framed in white.
+pub fn vertice_from_colors(colors:&[(u8, u8, u8)], positions:&[(f32, f32)],
+                           current:(u8, u8, u8)) -> Vec<Vertex> {
+    let mut ret = Vec::new();
+    for (&(r, g, b), &(x, y)) in colors.iter().zip(positions) {
+        if (r, g, b) == current {
+            ret.extend(quad((x - FRAME, y - FRAME), (x + SIZE + FRAME, y + SIZE + FRAME),
+                [255, 255, 255, 255]));
+        }
+        ret.extend(quad((x, y), (x + SIZE, y + SIZE), [r, g, b, 255]));
+    }
+    ret
+}
@@ -0,0 +1,14 @@
+in vec2 pos;
+in vec4 color;
+
+uniform mat3 view;
+
+out vec4 fcolor;
+
+void main()
+{
+    vec3 fpos = view * vec3(pos, 1.0);
+    gl_Position = vec4(fpos.x, fpos.y, 0.0, 1.0);
+
+    fcolor = color;
+}
@@ -1,7 +1,8 @@
 mod shader;
 
 use std::{cell::Cell, path::Path, fs, collections::{HashMap, BTreeMap}};
-use rusttype::{Font, Point, Scale};
+use rusttype::{Font, Point, Scale, point};
+use image::GrayImage;
 use luminance::{
     texture::{Texture, GenMipmaps, Sampler, Dim2},
     pixel::NormR8UI,
@@ -9,6 +10,7 @@ use luminance::{
 };
 
 pub use shader::*;
+use crate::bitmap2d::BitMap2D;
 
 pub struct GlyphRect {
     pub topleft: (f32, f32),
@@ -23,55 +25,113 @@ impl Into<usize> for FontID {
     fn into(self) -> usize { self.0 }
 }
 
+/// Color of the texts rendered without a color.
+pub const WHITE : [u8; 4] = [255, 255, 255, 255];
+
+/// Font used when no other one is loaded, built into the program (DejaVu Sans Mono, see
+/// `DejaVuSansMono.LICENSE`).
+pub const DEFAULT_FONT : &[u8] = include_bytes!("DejaVuSansMono.ttf");
+
+/// Resolution of the atlas the sizes of the rendered texts on screen are given for. With a higher
+/// resolution, texts keep their size but are sharper.
+const BASE_RESOLUTION : f32 = 64.0;
+
 pub struct TextRenderer {
     pub atlas: Texture<Dim2, NormR8UI>,
     pub glyphs: BTreeMap<(char, FontID), GlyphRect>,
 
     text_cache: Cell<HashMap<(String, FontID), Vec<Vertex>>>,
+
+    // the fonts and the resolution the atlas was built with, to build it again
+    builder: TextRendererBuilder,
+    sampler: Sampler,
 }
 
 impl TextRenderer {
 
-    pub fn render_text<S:AsRef<str>>(&self, text:S, mut pos:(f32, f32), id:FontID) -> Vec<Vertex> {
+    /// Scale of the glyphs of the atlas on screen.
+    fn scale(&self) -> f32 {
+        2.0 * BASE_RESOLUTION / self.builder.resolution as f32
+    }
+
+    /// Rasterize the loaded fonts again with another resolution, that is the size in pixels of
+    /// the glyphs in the atlas.
+    pub fn rebuild<C:GraphicsContext>(&mut self, ctx:&mut C, resolution:u32) -> Option<()> {
+        let mut builder = self.builder.clone();
+        builder.resolution = resolution;
+        *self = builder.build(ctx, self.sampler)?;
+        Some(())
+    }
+
+    /// Load a font file, rasterizing all the fonts again.
+    pub fn add_font<C, P>(&mut self, ctx:&mut C, file:P) -> Option<FontID>
+        where C : GraphicsContext,
+              P : AsRef<Path>,
+    {
+        let mut builder = self.builder.clone();
+        let id = builder.add_font(file)?;
+        *self = builder.build(ctx, self.sampler)?;
+        Some(id)
+    }
+
+    /// Return one of the loaded fonts.
+    pub fn font(&self, id:FontID) -> Option<Font> {
+        self.builder.font(id)
+    }
+
+    /// Build the quads of a text in white.
+    pub fn render_text<S:AsRef<str>>(&self, text:S, pos:(f32, f32), id:FontID) -> Vec<Vertex> {
+        self.render_colored_text(text, pos, id, WHITE)
+    }
+
+    /// Build the quads of a text in the given RGBA color.
+    pub fn render_colored_text<S:AsRef<str>>(&self, text:S, mut pos:(f32, f32), id:FontID,
+                                             color:[u8; 4]) -> Vec<Vertex> {
         let [aw, ah] = self.atlas.size();
+        let scale = self.scale();
         text.as_ref()
             .chars()
             .map(|c| {
                 let rect = self.glyphs.get(&(c, id));
 
                 rect.map(|rect| {
-                    let scale = 2.0;
 
                     let (x,y) = rect.topleft;
                     let (w,h) = rect.size;
                     let (sx,sy) = pos;
                     let (sw,sh) = (w * aw as f32 * scale, h * ah as f32 * scale);
                     pos = (sx + sw, sy);
-                    let sy = sy + rect.y_offset * 32.0 * scale;
+                    let sy = sy + rect.y_offset * BASE_RESOLUTION;
                     vec![
                         Vertex {
                             pos: VP::new([sx, sy]),
                             texPos: TP::new([x, y]),
+                            color: TC::new(color),
                         },
                         Vertex {
                             pos: VP::new([sx, sy + sh]),
                             texPos: TP::new([x, y+h]),
+                            color: TC::new(color),
                         },
                         Vertex {
                             pos: VP::new([sx + sw, sy + sh]),
                             texPos: TP::new([x+w, y+h]),
+                            color: TC::new(color),
                         },
                         Vertex {
                             pos: VP::new([sx + sw, sy + sh]),
                             texPos: TP::new([x+w, y+h]),
+                            color: TC::new(color),
                         },
                         Vertex {
                             pos: VP::new([sx + sw, sy]),
                             texPos: TP::new([x+w, y]),
+                            color: TC::new(color),
                         },
                         Vertex {
                             pos: VP::new([sx, sy]),
                             texPos: TP::new([x, y]),
+                            color: TC::new(color),
                         }
                     ]
                 })
@@ -86,6 +146,20 @@ impl TextRenderer {
             .collect()
     }
 
+    /// Width on screen of a text rendered by `render_text`.
+    pub fn text_width<S:AsRef<str>>(&self, text:S, id:FontID) -> f32 {
+        let [aw, _] = self.atlas.size();
+        let scale = self.scale();
+        text.as_ref()
+            .chars()
+            .map(|c| match self.glyphs.get(&(c, id)) {
+                Some(rect) => rect.size.0 * aw as f32 * scale,
+                None if c == ' ' => 10.0,
+                None => 0.0,
+            })
+            .sum()
+    }
+
     pub fn render_text_cached<'a, S:AsRef<str>>(& 'a self, text:S, pos:(f32,f32), id:FontID) -> & 'a [Vertex] {
         let map = unsafe { self.text_cache.as_ptr().as_mut().unwrap() };
         map.entry((text.as_ref().to_string(), id))
@@ -96,6 +170,39 @@ impl TextRenderer {
 }
 
 
+/// Rasterize a line of text with a font, its lines being the given number of pixels high. The
+/// pixels covered by more than half are set, for a sharp pixel art text.
+pub fn rasterize(text:&str, font:&Font, height:f32) -> GrayImage {
+    let scale = Scale::uniform(height);
+    let ascent = font.v_metrics(scale).ascent;
+    let glyphs : Vec<_> = font.layout(text, scale, point(0.0, ascent)).collect();
+
+    let width = glyphs
+        .iter()
+        .filter_map(|glyph| glyph.pixel_bounding_box())
+        .map(|bb| bb.max.x)
+        .max()
+        .unwrap_or(0)
+        .max(0) as u32;
+    let mut bitmap = GrayImage::new(width, height.ceil() as u32);
+
+    for glyph in glyphs {
+        if let Some(bb) = glyph.pixel_bounding_box() {
+            glyph.draw(|x, y, v| {
+                let (x, y) = (x as i32 + bb.min.x, y as i32 + bb.min.y);
+                let inside = x >= 0 && y >= 0
+                    && (x as u32) < bitmap.width() && (y as u32) < bitmap.height();
+                if inside && v >= 0.5 {
+                    bitmap.set_bit(x as usize, y as usize);
+                }
+            });
+        }
+    }
+
+    bitmap
+}
+
+#[derive(Clone)]
 pub struct TextRendererBuilder {
     fonts: Vec<Vec<u8>>,
     resolution: u32,
@@ -109,15 +216,29 @@ impl TextRendererBuilder {
         }
     }
 
+    /// Add a font file to the fonts rasterized by `build`. Files which are not valid fonts are
+    /// rejected.
     pub fn add_font<P:AsRef<Path>>(&mut self, file:P) -> Option<FontID> {
-        let ret = FontID(self.fonts.len());
         let content : Vec<u8> = fs::read(file).ok()?;
+        self.add_font_bytes(&content)
+    }
+
+    /// Add a font, given the content of its file, to the fonts rasterized by `build`. Invalid
+    /// fonts are rejected.
+    pub fn add_font_bytes(&mut self, content:&[u8]) -> Option<FontID> {
+        let ret = FontID(self.fonts.len());
+        Font::from_bytes(content).ok()?;
 
-        self.fonts.push(content);
+        self.fonts.push(content.to_vec());
 
         Some(ret)
     }
 
+    /// Return one of the added fonts.
+    pub fn font(&self, id:FontID) -> Option<Font> {
+        Font::from_bytes(self.fonts.get(id.0)?.as_slice()).ok()
+    }
+
     pub fn build<C:GraphicsContext>(&self, ctx: &mut C, sampler: Sampler) -> Option<TextRenderer> {
         let chars : Vec<(usize, char)> = (33..127u8).map(|n| n as char).enumerate().collect();
 
@@ -151,21 +272,28 @@ impl TextRendererBuilder {
                     .glyph(*c)
                     .scaled(Scale::uniform(self.resolution as f32))
                     .positioned(Point { x:x as f32, y:y as f32 });
-                let bb = glyph.pixel_bounding_box()?;
-                let (w, h) = (bb.width() as u32, bb.height() as u32);
+                // a glyph missing from a font is skipped, and a glyph larger than its cell is cut so
+                // that it does not overlap the next ones
+                let bb = match glyph.pixel_bounding_box() {
+                    Some(bb) => bb,
+                    None => continue,
+                };
+                let (w, h) = ((bb.width() as u32).min(res), (bb.height() as u32).min(res));
                 let num_pixels = (w * h) as usize;
                 let mut map : Vec<u8> = Vec::with_capacity(num_pixels);
                 map.resize(num_pixels, 0);
 
                 glyph.draw(|x, y, v| {
-                    let v = (v * 255f32) as u8;
-                    map[(y*w + x) as usize] = v;
+                    if x < w && y < h {
+                        let v = (v * 255f32) as u8;
+                        map[(y*w + x) as usize] = v;
+                    }
                 });
 
                 let (gx, gy) = (res * x as u32, fy + res * y as u32);
                 atlas.upload_part(GenMipmaps::No
                    , [gx, gy]
-                   , [bb.width() as u32, bb.height() as u32], &map).ok()?;
+                   , [w, h], &map).ok()?;
 
                 glyphs.insert((*c, FontID(fi)), GlyphRect {
                     topleft: (gx as f32 / aw as f32, gy as f32 / ah as f32),
@@ -179,6 +307,8 @@ impl TextRendererBuilder {
             atlas,
             glyphs,
             text_cache: Cell::new(HashMap::new()),
+            builder: self.clone(),
+            sampler,
         })
     }
 }
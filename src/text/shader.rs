@@ -13,6 +13,8 @@ pub enum Semantics {
     Position,
     #[sem(name="texPos", repr="[f32;2]", wrapper="TP")]
     TexPos,
+    #[sem(name="color", repr="[u8;4]", wrapper="TC")]
+    Color,
 }
 
 #[repr(C)]
@@ -21,6 +23,8 @@ pub enum Semantics {
 pub struct Vertex {
     pub pos: VP,
     pub texPos: TP,
+    #[vertex(normalized="true")]
+    pub color: TC,
 }
 
 #[derive(UniformInterface)]
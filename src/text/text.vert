@@ -1,5 +1,6 @@
 in vec2 pos;
 in vec2 texPos;
+in vec4 color;
 
 uniform sampler2D tex;
 uniform mat3 view;
@@ -12,7 +13,7 @@ void main()
     vec3 fpos = view * vec3(pos, 1);
     gl_Position = vec4(fpos.x, fpos.y, 0, 1.0);
 
-    fcolor = vec4(1,1,1,1);
+    fcolor = color;
 
     texcoord = texPos;
 }
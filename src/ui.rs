@@ -1,6 +1,6 @@
 use luminance_glfw::{GlfwSurface, Surface, WindowEvent, Action, Key};
-use std::{rc::Rc, collections::{HashMap, HashSet}};
-use crate::keyboard::{ModSet, KeyboardLayout, CharKeyMod, CharKey, Mod, azerty};
+use std::{rc::Rc, collections::{HashMap, HashSet}, time::{Duration, Instant}};
+use crate::keyboard::{ModSet, KeyboardLayout, CharKeyMod, CharKey, KeyParseError, Mod, azerty};
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum Mode {
@@ -15,8 +15,10 @@ pub struct KeySequence {
     seq:Vec<CharKeyMod>,
 }
 
-impl From<&str> for KeySequence {
-    fn from(s:&str) -> Self {
+impl KeySequence {
+    /// Parse a sequence of keys, such as `:w<CR>`, each key being a character or a key between
+    /// angle brackets as parsed by `CharKeyMod::try_from`.
+    pub fn try_from(s:&str) -> Result<Self, KeyParseError> {
         let mut chars = s.chars();
         let mut seq : Vec<CharKeyMod> = Vec::new();
 
@@ -25,46 +27,141 @@ impl From<&str> for KeySequence {
                 let mut s = '<'.to_string();
                 let chars = chars.by_ref();
                 while let Some(c) = chars.next() {
+                    s.push(c);
                     if c == '>' {
                         break;
                     }
-                    s.push(c);
                 }
 
-                s.push('>');
-                seq.push(CharKeyMod::from(s.as_ref()));
+                seq.push(CharKeyMod::try_from(s.as_ref())?);
             } else {
-                seq.push(CharKeyMod::from(c.to_string().as_ref()));
+                seq.push(CharKeyMod::try_from(c.to_string().as_ref())?);
             }
         }
 
-        Self { seq }
+        Ok(Self { seq })
+    }
+}
+
+/// Split a command line into words separated by whitespace. A word can be quoted with double
+/// quotes to hold whitespace, such as `w "My File.png"`, and a backslash makes the next
+/// character part of the word whatever it is, such as `\"` or `\ `.
+pub fn split_command(line:&str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word : Option<String> = None;
+    let mut quoted = false;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let escaped = chars.next().unwrap_or('\\');
+                word.get_or_insert_with(String::new).push(escaped);
+            },
+            '"' => {
+                quoted = !quoted;
+                word.get_or_insert_with(String::new);
+            },
+            c if c.is_whitespace() && !quoted => {
+                words.extend(word.take());
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
     }
+    words.extend(word);
+
+    words
 }
 
-pub type UiCommand<T> = dyn Fn(&mut Ui<T>, &mut T, &Vec<&str>);
+pub type UiCommand<T> = dyn Fn(&mut Ui<T>, &mut T, &Vec<&str>) -> Result<(), String>;
 pub type UiVerb<T> = dyn Fn(&mut Ui<T>, &mut T, Option<&HashSet<(usize, usize)>>);
 pub type UiObject<T> = dyn Fn(&mut Ui<T>, &T, &mut HashSet<(usize, usize)>);
 pub type UiCharProcessor<T> = dyn Fn(&mut Ui<T>, &mut T, CharKeyMod);
 
+/// The last change done by the user, repeated by `.`: a verb applied count times, on the
+/// positions given by an object moved count times if the verb is transitive. A verb entering
+/// insertion mode comes with the keys typed until the mode was left.
+struct Change<T> {
+    count: usize,
+    verb: Rc<UiVerb<T>>,
+    object: Option<(usize, Rc<UiObject<T>>)>,
+    inserted: Vec<CharKeyMod>,
+}
+
+impl<T> Clone for Change<T> {
+    fn clone(&self) -> Self {
+        Self {
+            count: self.count,
+            verb: self.verb.clone(),
+            object: self.object.clone(),
+            inserted: self.inserted.clone(),
+        }
+    }
+}
+
 pub struct Ui<T> {
     commands: HashMap<String, Rc<UiCommand<T>>>,
-    verbs: HashMap<CharKeyMod, (bool, Rc<UiVerb<T>>)>,
-    objects: HashMap<CharKeyMod, Rc<UiObject<T>>>,
+    // verbs with whether they are transitive and whether they can be repeated by `.`
+    verbs: HashMap<CharKeyMod, (bool, bool, Rc<UiVerb<T>>)>,
+    objects: HashMap<Vec<CharKeyMod>, Rc<UiObject<T>>>,
     char_processor: Rc<UiCharProcessor<T>>,
 
-    bindings: HashMap<(CharKeyMod, Mode), KeySequence>,
+    bindings: HashMap<(Vec<CharKeyMod>, Mode), KeySequence>,
+    // key standing for `<leader>` in bindings, and the typed keys that may start a binding
+    leader: CharKeyMod,
+    pending_keys: Vec<CharKeyMod>,
+    // when the last key was typed while keys are pending, and how long to wait for the next one
+    // before running the binding they already match
+    pending_since: Option<Instant>,
+    timeout: Duration,
+    // whether AltGr is held down, the other modifiers being given by the key events
     modset:ModSet,
 
-    window_event_listener: Option<Rc<dyn Fn(&mut T, WindowEvent)>>,
+    window_event_listener: Option<Rc<dyn Fn(&mut Ui<T>, &mut T, WindowEvent)>>,
     // buffer for storing unprocessed chars waiting
     buffer: String,
+    // message reported to the user, until the next key is pressed
+    message: Option<String>,
 
     // typed verb waiting for an object to come (if transitive)
-    verb: Option<(usize, Rc<UiVerb<T>>)>,
+    verb: Option<(usize, bool, Rc<UiVerb<T>>)>,
+    // last change repeated by `.`, and the change which entered insertion mode, recording the
+    // keys typed until the mode is left
+    last_change: Option<Change<T>>,
+    insertion: Option<Change<T>>,
+    // typed operator waiting for its one-character argument, such as `m` waiting for a mark name
+    pending: Option<char>,
+    // count and keys typed so far of an object made of several keys, such as the first `g` of `gg`
+    object_keys: Option<(Option<usize>, Vec<CharKeyMod>)>,
+
+    marks: HashMap<char, (usize, usize)>,
+
+    // entered commands, oldest first, the one being shown while browsing them and the command
+    // typed before browsing them
+    command_history: Vec<String>,
+    history_index: Option<usize>,
+    typed_command: String,
+    // last command run, run again by `@:`
+    last_command: Option<String>,
+    // command names matching the completed prefix and the one in the buffer, while `<Tab>` is
+    // being pressed
+    completion: Option<(Vec<String>, usize)>,
+
+    // macros: the keys recorded in each register, the register being recorded, the last register
+    // played and how many macros are being played at the moment
+    registers: HashMap<char, Vec<CharKeyMod>>,
+    recording: Option<char>,
+    last_played: Option<char>,
+    playing: usize,
 
     mode: Mode,
     running: bool,
+    // whether events were received by the last call to `input`
+    received_events: bool,
+    // whether the cursor moving out of the canvas wraps around rather than stopping at its border
+    wrap: bool,
+    // columns and rows the moving cursor snaps to, and the largest distance it snaps from
+    snap: Option<(Vec<usize>, Vec<usize>, usize)>,
     cursor: (usize, usize),
     saved_cursor: (usize, usize),
     layout:KeyboardLayout,
@@ -79,6 +176,10 @@ impl<T> Ui<T> {
             verbs: HashMap::new(),
             objects: HashMap::new(),
             bindings: HashMap::new(),
+            leader: CharKeyMod::from("<Space>"),
+            pending_keys: Vec::new(),
+            pending_since: None,
+            timeout: Duration::from_millis(300),
 
             window_event_listener: None,
 
@@ -86,155 +187,416 @@ impl<T> Ui<T> {
             char_processor: Rc::new(f),
 
             buffer: String::new(),
+            message: None,
             verb: None,
+            last_change: None,
+            insertion: None,
+            pending: None,
+            object_keys: None,
+            marks: HashMap::new(),
+
+            command_history: Vec::new(),
+            history_index: None,
+            typed_command: String::new(),
+            last_command: None,
+            completion: None,
+
+            registers: HashMap::new(),
+            recording: None,
+            last_played: None,
+            playing: 0,
 
             mode: Mode::Normal,
             running: true,
+            received_events: false,
+            wrap: true,
+            snap: None,
             cursor: (0, 0),
             saved_cursor: (0, 0),
         }
     }
 
     pub fn input(&mut self, glfw: &mut GlfwSurface, env: &mut T) -> bool {
+        self.received_events = false;
         for evt in glfw.poll_events() {
+            self.received_events = true;
             match evt {
                 WindowEvent::Close => self.running = false,
 
                 // Every other key pressed will update the buffer and the state of the Ui.
-                WindowEvent::Key(k, _, act, _) if act != Action::Release => {
-                    match k {
-                        Key::LeftShift | Key::RightShift => self.modset.set(Mod::Shift),
-                        Key::LeftControl | Key::RightControl => self.modset.set(Mod::Control),
-                        Key::LeftAlt => self.modset.set(Mod::Alt),
-                        Key::RightAlt => self.modset.set(Mod::AltGr),
-                        _ => {},
+                WindowEvent::Key(k, _, act, modifiers) if act != Action::Release => {
+                    self.message = None;
+
+                    if k == Key::RightAlt {
+                        self.modset.set(Mod::AltGr);
                     }
 
-                    if let Some(code) = self.layout.translate(&(k, self.modset)).clone() {
-                        let key_mod = CharKeyMod { key: code, mods: self.modset };
-                        let pair = (key_mod, self.mode);
+                    // the modifiers are the ones of the event, but for AltGr which GLFW reports
+                    // as Alt and which is only known from its own key
+                    let mut mods = ModSet::from(modifiers);
+                    if self.modset.is_set(Mod::AltGr) {
+                        mods.clear(Mod::Alt);
+                        mods.set(Mod::AltGr);
+                    }
 
-                        if let Some(KeySequence { seq }) = self.bindings.get(&pair) {
-                            for CharKeyMod { key, mods } in seq.clone() {
-                                self.perform_char_mod(env, key, mods);
-                            }
-                        } else {
-                            self.perform_char_mod(env, code, self.modset)
-                        }
+                    if let Some(code) = self.layout.translate(&(k, mods)).clone() {
+                        let key_mod = CharKeyMod { key: code, mods };
+                        self.dispatch_key(env, key_mod);
                     }
                 },
 
                 WindowEvent::Key(k, _, Action::Release, _) => {
-                    match k {
-                        Key::LeftShift | Key::RightShift => self.modset.clear(Mod::Shift),
-                        Key::LeftControl | Key::RightControl => self.modset.clear(Mod::Control),
-                        Key::LeftAlt => self.modset.clear(Mod::Alt),
-                        Key::RightAlt => self.modset.clear(Mod::AltGr),
-                        _ => {},
+                    if k == Key::RightAlt {
+                        self.modset.clear(Mod::AltGr);
                     }
                 },
 
                 e => {
-                    let _ = self.window_event_listener.as_ref().map(|f| {
-                        let f = f.clone();
-                        f(env, e)
-                    });
+                    if let Some(f) = self.window_event_listener.clone() {
+                        f(self, env, e);
+                    }
                 },
             }
         }
 
+        // keys waiting too long for a longer binding run the binding they match
+        if self.pending_since.map_or(false, |since| since.elapsed() >= self.timeout) {
+            self.received_events = true;
+            self.resolve_pending_keys(env, true);
+        }
+
         self.running
     }
 
-    fn launch_command(&mut self, env:&mut T, command:String) {
-        let mut words = command.split_whitespace();
+    /// Process a key pressed by the user, or replayed from a macro.
+    fn dispatch_key(&mut self, env:&mut T, key_mod:CharKeyMod) {
+        if let (Some(register), 0) = (self.recording, self.playing) {
+            self.registers.entry(register).or_insert_with(Vec::new).push(key_mod);
+        }
+
+        self.pending_keys.push(key_mod);
+        self.resolve_pending_keys(env, false);
+    }
+
+    /// Run the bindings matching the keys typed so far, the longest one first. While the keys
+    /// could still become a longer binding, wait for the next key, unless the wait `timed_out`.
+    /// Keys not starting any binding are processed one by one.
+    fn resolve_pending_keys(&mut self, env:&mut T, mut timed_out:bool) {
+        self.pending_since = None;
+        while !self.pending_keys.is_empty() {
+            let mode = self.mode;
+            let keys = &self.pending_keys;
+
+            let waiting = self.bindings
+                .keys()
+                .any(|(seq, m)| *m == mode && seq.len() > keys.len() && seq.starts_with(keys));
+            if waiting && !timed_out {
+                self.pending_since = Some(Instant::now());
+                return
+            }
+            timed_out = false;
+
+            let bound = (1..keys.len() + 1)
+                .rev()
+                .find(|&n| self.bindings.contains_key(&(keys[..n].to_vec(), mode)));
+
+            match bound {
+                Some(n) => {
+                    let keys : Vec<CharKeyMod> = self.pending_keys.drain(..n).collect();
+                    let KeySequence { seq } = &self.bindings[&(keys, mode)];
+                    for CharKeyMod { key, mods } in seq.clone() {
+                        self.perform_char_mod(env, key, mods);
+                    }
+                },
+                None => {
+                    let CharKeyMod { key, mods } = self.pending_keys.remove(0);
+                    self.perform_char_mod(env, key, mods);
+                },
+            }
+        }
+    }
+
+    /// Replay the keys recorded in a register.
+    fn play_macro(&mut self, env:&mut T, register:char) {
+        // a macro playing itself would never stop
+        const MAX_NESTED_MACROS : usize = 100;
+
+        let keys = match self.registers.get(&register) {
+            Some(keys) if self.playing < MAX_NESTED_MACROS => keys.clone(),
+            _ => return,
+        };
+
+        self.last_played = Some(register);
+        self.playing += 1;
+        for key_mod in keys {
+            self.dispatch_key(env, key_mod);
+        }
+        self.playing -= 1;
+    }
+
+    /// Add an entered command to the history, unless it was the last one entered.
+    fn push_history(&mut self, command:&str) {
+        // number of commands kept in the history
+        const HISTORY_SIZE : usize = 100;
 
-        let name = words.next().unwrap();
-        let args = words.collect();
+        self.history_index = None;
+        let last = self.command_history.last().map(String::as_str);
+        if command.trim().is_empty() || last == Some(command) {
+            return
+        }
 
-        if let Some(command) = self.commands.get(name) {
-            let command = command.clone();
-            command(self, env, &args);
+        self.command_history.push(command.to_string());
+        if self.command_history.len() > HISTORY_SIZE {
+            self.command_history.remove(0);
+        }
+    }
+
+    /// Replace the buffer with an older or newer command of the history. Going past the newest
+    /// one restores the command being typed.
+    fn browse_history(&mut self, older:bool) {
+        let len = self.command_history.len();
+        let index = match (self.history_index, older) {
+            (_, true) if len == 0 => return,
+            (None, true) => {
+                self.typed_command = self.buffer.clone();
+                Some(len - 1)
+            },
+            (Some(i), true) => Some(i.saturating_sub(1)),
+            (Some(i), false) if i + 1 < len => Some(i + 1),
+            (Some(_), false) => None,
+            (None, false) => return,
+        };
+
+        self.history_index = index;
+        self.buffer = match index {
+            Some(i) => self.command_history[i].clone(),
+            None => std::mem::take(&mut self.typed_command),
+        };
+    }
+
+    /// Complete the name of the command in the buffer. When several commands match, they are
+    /// listed in the message and each new completion gives the next one.
+    fn complete_command(&mut self) {
+        if self.buffer.contains(char::is_whitespace) {
+            return
+        }
+
+        let (candidates, index) = match self.completion.take() {
+            Some((candidates, index)) => {
+                let index = (index + 1) % candidates.len();
+                (candidates, index)
+            },
+            None => {
+                let candidates : Vec<String> = self.command_names()
+                    .into_iter()
+                    .filter(|name| name.starts_with(self.buffer.as_str()))
+                    .cloned()
+                    .collect();
+
+                if candidates.is_empty() {
+                    return
+                }
+                (candidates, 0)
+            },
+        };
+
+        self.buffer = candidates[index].clone();
+        if candidates.len() > 1 {
+            let list : Vec<String> = candidates
+                .iter()
+                .enumerate()
+                .map(|(i, name)| if i == index { format!("[{}]", name) } else { name.clone() })
+                .collect();
+            self.set_message(list.join(" "));
+        }
+
+        self.completion = Some((candidates, index));
+    }
+
+    /// Parse and run a single command line, such as `color q #ff8800`. Empty lines are ignored.
+    pub fn run_command_line(&mut self, env:&mut T, line:&str) {
+        if !line.trim().is_empty() {
+            self.launch_command(env, line.to_string());
+        }
+    }
+
+    /// Run the last command again, such as with `@:`. The command sees the selection and the
+    /// cursor as they are now.
+    pub fn repeat_last_command(&mut self, env:&mut T) {
+        match self.last_command.clone() {
+            Some(command) => self.launch_command(env, command),
+            None => self.set_message("No previous command"),
+        }
+    }
+
+    fn launch_command(&mut self, env:&mut T, command:String) {
+        let words = split_command(&command);
+        let name = match words.first() {
+            Some(name) => name.as_str(),
+            None => return,
+        };
+
+        if name == "@:" {
+            self.repeat_last_command(env);
+            return
+        }
+        self.last_command = Some(command.clone());
+        let args = words[1..].iter().map(String::as_str).collect();
+
+        let command = match self.commands.get(name) {
+            Some(command) => command.clone(),
+            None => {
+                self.set_message(format!("Not a command: {}", name));
+                return
+            },
+        };
+
+        if let Err(e) = command(self, env, &args) {
+            self.set_message(e);
         }
     }
 
     fn perform_char_mod(&mut self, env:&mut T, c:CharKey, mods:ModSet) {
+        if c != CharKey::Special(28) {
+            self.completion = None;
+        }
+
         match c {
 
             CharKey::Special(0) => {
+                // leaving insertion mode ends the change which entered it, if it inserted keys
+                if self.mode == Mode::Insertion {
+                    if let Some(change) = self.insertion.take() {
+                        if !change.inserted.is_empty() {
+                            self.last_change = Some(change);
+                        }
+                    }
+                }
+
                 self.cursor = self.saved_cursor;
                 self.set_mode(Mode::Normal);
                 self.buffer.clear();
                 self.verb = None;
-                if let Some((_, action)) = self.verbs.get(&CharKeyMod { key:c, mods }) {
+                self.pending = None;
+                self.object_keys = None;
+                self.history_index = None;
+                if let Some((_, _, action)) = self.verbs.get(&CharKeyMod { key:c, mods }) {
                     (action.clone())(self, env, None);
                 }
             },
 
             CharKey::Special(24) if self.mode == Mode::Command => {
                 let s = std::mem::replace(&mut self.buffer, String::new());
-                self.launch_command(env, s);
+                self.push_history(&s);
+                self.run_command_line(env, &s);
             },
+            // previous and next entered commands
+            CharKey::Special(22) if self.mode == Mode::Command => self.browse_history(true),
+            CharKey::Special(21) if self.mode == Mode::Command => self.browse_history(false),
+            CharKey::Special(28) if self.mode == Mode::Command => self.complete_command(),
             // any character in insertion mode
             c if self.mode == Mode::Insertion => {
+                if let Some(change) = self.insertion.as_mut() {
+                    change.inserted.push(CharKeyMod { key:c, mods });
+                }
                 let processor = self.char_processor.clone();
                 processor(self, env, CharKeyMod { key:c, mods })
             },
 
-            // number in non-insertion mode
-            CharKey::Char(c) if c.is_ascii_digit() => {
+            // argument of an operator in non-insertion mode
+            CharKey::Char(c) if self.pending.is_some() && self.mode != Mode::Command => {
+                let operator = self.pending.take().unwrap();
+                self.perform_pending(env, operator, c);
+            },
+
+            // stop recording a macro, without recording the key stopping it
+            CharKey::Char('q') if self.recording.is_some() && self.mode != Mode::Command
+                && self.verb.is_none() => {
+                if let Some(keys) = self.recording.take().and_then(|r| self.registers.get_mut(&r)) {
+                    keys.pop();
+                }
+            },
+
+            // number in non-insertion mode, a leading zero being a motion rather than a count
+            CharKey::Char(c) if c.is_ascii_digit() && (c != '0' || !self.buffer.is_empty()) => {
                 self.buffer.push(c);
             },
 
+            // operator waiting for an argument in normal mode
+            CharKey::Char(c) if Self::is_operator(c) && !mods.is_set(Mod::Control)
+                && !mods.is_set(Mod::Alt) && self.verb.is_none() && self.object_keys.is_none()
+                && self.mode != Mode::Command => {
+                self.pending = Some(c);
+            },
+
             // any character in normal mode
             c if self.mode == Mode::Normal || self.mode == Mode::Visual => {
-                // parse count
-                let count = if self.buffer.len() == 0 { 1 }
-                            else { self.buffer.parse().unwrap() };
-
-                self.buffer.clear();
+                let key = CharKeyMod { key:c, mods };
+                let (given_count, keys) = match self.object_keys.take() {
+                    Some((count, mut keys)) => {
+                        keys.push(key);
+                        (count, keys)
+                    },
+                    None => (self.take_count(), vec![key]),
+                };
+
+                // keys starting an object made of several keys wait for the next ones, unless
+                // they are a verb
+                let is_verb = self.verb.is_none() && keys.len() == 1
+                    && self.verbs.contains_key(&key);
+                let starts_object = !self.objects.contains_key(&keys) && self.objects
+                    .keys()
+                    .any(|object| object.len() > keys.len() && object.starts_with(&keys));
+                if starts_object && !is_verb {
+                    self.object_keys = Some((given_count, keys));
+                    return
+                }
+                let count = given_count.unwrap_or(1);
 
                 // if we already had a verb
-                // then the char is an object
-                if let Some((n, v)) = self.verb.take() {
-                    let verb = v.clone();
-                    let obj = CharKeyMod { key:c, mods };
-                    let object = self.objects.get(&obj);
-
-                    if let Some(object) = object {
-                        let object = object.clone();
-                        let mut positions = HashSet::new();
-
-                        // for each asked application
-                        for _ in 0..n {
-                            positions.clear();
-
-                            // move count times
-                            for _ in 0..count {
-                                object(self, env, &mut positions);
-                            }
-
-                            // apply verb on given positions
-                            verb(self, env, Some(&positions));
+                // then the keys are an object
+                if let Some((n, repeatable, v)) = self.verb.take() {
+                    if let Some(object) = self.objects.get(&keys) {
+                        let object = Some((count, object.clone()));
+                        let change = Change { count:n, verb:v, object, inserted:Vec::new() };
+                        self.apply_change(env, &change);
+
+                        if repeatable {
+                            self.last_change = Some(change);
+                        }
+                    }
+                } else if keys.len() == 1 && c == CharKey::Char('.') && !mods.is_set(Mod::Control)
+                    && !mods.is_set(Mod::Alt) {
+                    // repeat the last change, with the new count if one was given
+                    if let Some(mut change) = self.last_change.clone() {
+                        if let Some(count) = given_count {
+                            change.count = count;
                         }
+
+                        self.apply_change(env, &change);
                     }
                 } else { // else, we have a verb, we need to check for transitivity
 
 
                     // check if verb exist
-                    let verb = CharKeyMod { key:c, mods };
-                    let object = verb.clone();
-                    if let Some((is_transitive, action)) = self.verbs.get(&verb) {
+                    if let Some((is_transitive, repeatable, action)) = self.verbs.get(&key)
+                        .filter(|_| is_verb) {
 
                         if *is_transitive {
-                            self.verb = Some((count, action.clone()));
+                            self.verb = Some((count, *repeatable, action.clone()));
                         } else {
-                            let act = action.clone();
-                            for _ in 0..count {
-                                act(self, env, None);
+                            let verb = action.clone();
+                            let change = Change { count, verb, object:None, inserted:Vec::new() };
+                            let repeatable = *repeatable;
+                            self.apply_change(env, &change);
+
+                            if self.mode == Mode::Insertion {
+                                self.insertion = Some(change);
+                            } else if repeatable {
+                                self.last_change = Some(change);
                             }
                         }
-                    } else if let Some(object) = self.objects.get(&object) {
+                    } else if let Some(object) = self.objects.get(&keys) {
                         let object = object.clone();
                         let mut v = HashSet::new();
                         for _ in 0..count {
@@ -250,31 +612,136 @@ impl<T> Ui<T> {
         }
     }
 
-    pub fn add_verb<B, F>(&mut self, verb:B, transitive:bool, f:F)
+    /// Apply a verb count times, on the positions of its object if it has one. The keys inserted
+    /// by the change are typed again after the verb each time, then insertion mode is left.
+    fn apply_change(&mut self, env:&mut T, change:&Change<T>) {
+        let mut positions = HashSet::new();
+
+        for _ in 0..change.count {
+            match &change.object {
+                Some((count, object)) => {
+                    positions.clear();
+
+                    // move count times
+                    for _ in 0..*count {
+                        object(self, env, &mut positions);
+                    }
+
+                    // apply verb on given positions
+                    (change.verb)(self, env, Some(&positions));
+                },
+                None => (change.verb)(self, env, None),
+            }
+
+            if !change.inserted.is_empty() && self.mode == Mode::Insertion {
+                let processor = self.char_processor.clone();
+                for &key_mod in &change.inserted {
+                    processor(self, env, key_mod);
+                }
+                self.perform_char_mod(env, CharKey::Special(0), ModSet::empty());
+            }
+        }
+    }
+
+    fn is_operator(c:char) -> bool {
+        c == 'm' || c == '`' || c == 'q' || c == '@'
+    }
+
+    fn perform_pending(&mut self, env:&mut T, operator:char, c:char) {
+        match operator {
+            // set a mark
+            'm' => {
+                self.marks.insert(c, self.cursor);
+            },
+            // jump to a mark
+            '`' => {
+                if let Some(&(x, y)) = self.marks.get(&c) {
+                    self.set_cursor(x, y);
+                }
+            },
+            // start recording a macro
+            'q' => {
+                self.registers.insert(c, Vec::new());
+                self.recording = Some(c);
+            },
+            // run the last command again
+            '@' if c == ':' => self.repeat_last_command(env),
+            // play a macro, `@@` playing the last one again
+            '@' => {
+                let register = if c == '@' { self.last_played } else { Some(c) };
+                if let Some(register) = register {
+                    self.play_macro(env, register);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /// Add a verb, applied on an object if it is transitive. Only repeatable verbs, the ones
+    /// changing the canvas, are repeated by `.`.
+    pub fn add_verb<B, F>(&mut self, verb:B, transitive:bool, repeatable:bool, f:F)
         where F : Fn(&mut Ui<T>, &mut T, Option<&HashSet<(usize, usize)>>) + 'static,
               B : Into<CharKeyMod>,
     {
-        let _ = self.verbs.insert(verb.into(), (transitive, Rc::new(f)));
+        let _ = self.verbs.insert(verb.into(), (transitive, repeatable, Rc::new(f)));
+    }
+
+    /// Names of the registered commands, sorted.
+    pub fn command_names(&self) -> Vec<&String> {
+        let mut names : Vec<&String> = self.commands.keys().collect();
+        names.sort();
+        names
     }
 
-    pub fn add_object<O, F>(&mut self, obj:O, f:F)
+    /// Add an object, typed with one key or more, such as `G` or `gg`.
+    pub fn add_object<F>(&mut self, keys:&str, f:F)
         where F : (Fn(&mut Ui<T>, &T, &mut HashSet<(usize, usize)>)) + 'static,
-              O : Into<CharKeyMod>,
     {
-        let _ = self.objects.insert(obj.into(), Rc::new(f));
+        let keys = KeySequence::try_from(keys)
+            .unwrap_or_else(|e| panic!("Invalid object \"{}\": {}", keys, e));
+        let _ = self.objects.insert(keys.seq, Rc::new(f));
     }
 
+    /// Add a command, run by typing its name and its arguments in command mode. The error it
+    /// returns, such as its usage, is shown instead of the typed command.
     pub fn add_command<S, F>(&mut self, name:S, f:F)
-        where F : Fn(&mut Ui<T>, &mut T, &Vec<&str>) + 'static,
+        where F : Fn(&mut Ui<T>, &mut T, &Vec<&str>) -> Result<(), String> + 'static,
               S : Into<String>,
     {
         let _ = self.commands.insert(name.into(), Rc::new(f));
     }
 
-    pub fn bind_key<K:Into<CharKeyMod>, S:Into<KeySequence>>(&mut self, k:K, mode:Mode, phrase:S) {
-        let k = k.into();
-        let s = phrase.into();
-        self.bindings.insert((k, mode), s);
+    /// Bind a sequence of keys to another one in the given mode. `<leader>` in the bound keys stands
+    /// for the leader key at the time of the binding.
+    pub fn bind_key(&mut self, keys:&str, mode:Mode, phrase:&str) {
+        self.try_bind_key(keys, mode, phrase)
+            .unwrap_or_else(|e| panic!("Invalid binding of \"{}\": {}", keys, e))
+    }
+
+    /// Bind a sequence of keys as `bind_key` does, failing if the keys cannot be parsed.
+    pub fn try_bind_key(&mut self, keys:&str, mode:Mode, phrase:&str)
+        -> Result<(), KeyParseError> {
+        let mut seq = Vec::new();
+        for (i, part) in keys.split("<leader>").enumerate() {
+            if i > 0 {
+                seq.push(self.leader);
+            }
+            seq.extend(KeySequence::try_from(part)?.seq);
+        }
+
+        self.bindings.insert((seq, mode), KeySequence::try_from(phrase)?);
+        Ok(())
+    }
+
+    /// Set the key `<leader>` stands for in the following bindings.
+    pub fn set_leader(&mut self, leader:CharKeyMod) {
+        self.leader = leader
+    }
+
+    /// Whether events were received by the last call to `input`, in which case the state may have
+    /// changed.
+    pub fn received_events(&self) -> bool {
+        self.received_events
     }
 
     pub fn close(&mut self) {
@@ -291,13 +758,89 @@ impl<T> Ui<T> {
     }
 
     pub fn wrapping_displace(&mut self, dx:isize, dy:isize, w:usize, h:usize) {
-        self.cursor.0 = ((self.cursor.0 as isize).wrapping_add(dx) as usize).min(w - 1);
-        self.cursor.1 = ((self.cursor.1 as isize).wrapping_add(dy) as usize).min(h - 1);
+        let x = ((self.cursor.0 as isize).wrapping_add(dx) as usize).min(w.saturating_sub(1));
+        let y = ((self.cursor.1 as isize).wrapping_add(dy) as usize).min(h.saturating_sub(1));
+        self.cursor = self.snapped(x, y);
+        if self.mode != Mode::Visual {
+            self.saved_cursor = self.cursor
+        }
+    }
+
+    /// Move the cursor, stopping at the border of a canvas of the given size.
+    pub fn clamping_displace(&mut self, dx:isize, dy:isize, w:usize, h:usize) {
+        let clamp = |v:usize, d:isize, max:usize| {
+            (v as isize).saturating_add(d).min(max as isize - 1).max(0) as usize
+        };
+        self.cursor = self.snapped(clamp(self.cursor.0, dx, w), clamp(self.cursor.1, dy, h));
         if self.mode != Mode::Visual {
             self.saved_cursor = self.cursor
         }
     }
 
+    /// Return when the typed keys waiting for a longer binding run the binding they match, if
+    /// keys are waiting.
+    pub fn pending_deadline(&self) -> Option<Instant> {
+        self.pending_since.map(|since| since + self.timeout)
+    }
+
+    /// Set how long typed keys matching a binding wait for the next key, in case they are the
+    /// start of a longer binding.
+    pub fn set_timeout(&mut self, timeout:Duration) {
+        self.timeout = timeout
+    }
+
+    /// Choose whether the cursor moved by `h`, `j`, `k` and `l` wraps around the canvas or stops at
+    /// its border.
+    pub fn set_wrap(&mut self, wrap:bool) {
+        self.wrap = wrap
+    }
+
+    pub fn wraps(&self) -> bool {
+        self.wrap
+    }
+
+    /// Make the moving cursor snap to the given columns and rows when it gets at most the given
+    /// distance from them, or stop it from snapping.
+    pub fn set_snap(&mut self, snap:Option<(Vec<usize>, Vec<usize>, usize)>) {
+        self.snap = snap
+    }
+
+    /// Return where the cursor moving to the given position ends up once snapped.
+    fn snapped(&self, x:usize, y:usize) -> (usize, usize) {
+        match &self.snap {
+            Some((columns, rows, distance)) => (
+                crate::grid::snap(self.cursor.0, x, columns.iter().cloned(), *distance),
+                crate::grid::snap(self.cursor.1, y, rows.iter().cloned(), *distance),
+            ),
+            None => (x, y),
+        }
+    }
+
+    /// Move the cursor to the given position, snapping it if it moves close to a snapped column
+    /// or row.
+    pub fn set_cursor(&mut self, x:usize, y:usize) {
+        self.cursor = self.snapped(x, y);
+        if self.mode != Mode::Visual {
+            self.saved_cursor = self.cursor
+        }
+    }
+
+    /// Bring the cursor and the marks back in a canvas of the given size.
+    pub fn clamp_cursor(&mut self, w:usize, h:usize) {
+        let (max_x, max_y) = (w.saturating_sub(1), h.saturating_sub(1));
+        let clamp = |(x, y):(usize, usize)| (x.min(max_x), y.min(max_y));
+        self.cursor = clamp(self.cursor);
+        self.saved_cursor = clamp(self.saved_cursor);
+        for mark in self.marks.values_mut() {
+            *mark = clamp(*mark);
+        }
+    }
+
+    /// Positions of the marks, by name.
+    pub fn marks_mut(&mut self) -> &mut HashMap<char, (usize, usize)> {
+        &mut self.marks
+    }
+
     pub fn set_mode(&mut self, mode:Mode) {
         self.mode = mode
     }
@@ -306,13 +849,20 @@ impl<T> Ui<T> {
         self.mode
     }
 
+    /// Position of the cursor when the visual mode was entered.
+    pub fn get_anchor(&self) -> (usize, usize) {
+        self.saved_cursor
+    }
+
     pub fn get_selection(&self) -> ((usize, usize), (usize, usize)) {
         let (x1, y1) = self.cursor;
         let (x2, y2) = self.saved_cursor;
         ((x1.min(x2), y1.min(y2)), (x1.max(x2), y1.max(y2)))
     }
 
-    pub fn set_window_event_listener<F:Fn(&mut T, WindowEvent) + 'static>(&mut self, f:Option<F>) {
+    pub fn set_window_event_listener<F>(&mut self, f:Option<F>)
+        where F : Fn(&mut Ui<T>, &mut T, WindowEvent) + 'static
+    {
         match f {
             Some(f) => {
                 self.window_event_listener = Some(Rc::new(f));
@@ -321,7 +871,109 @@ impl<T> Ui<T> {
         }
     }
 
+    /// Return the count typed before the current verb or object, if any, and forget it.
+    pub fn take_count(&mut self) -> Option<usize> {
+        if self.mode == Mode::Command || self.buffer.is_empty() {
+            return None
+        }
+
+        let count = self.buffer.parse().ok();
+        self.buffer.clear();
+        count
+    }
+
     pub fn get_buffer(&self) -> &String {
         &self.buffer
     }
+
+    /// Report a message to the user, for instance the outcome of a command.
+    pub fn set_message<S:Into<String>>(&mut self, msg:S) {
+        self.message = Some(msg.into())
+    }
+
+    pub fn get_message(&self) -> Option<&String> {
+        self.message.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_on_whitespace() {
+        assert_eq!(split_command("resize 4  8"), vec!["resize", "4", "8"]);
+        assert_eq!(split_command("  resize 4 8 \t "), vec!["resize", "4", "8"]);
+        assert_eq!(split_command(""), Vec::<String>::new());
+        assert_eq!(split_command("   "), Vec::<String>::new());
+    }
+
+    #[test]
+    fn split_quoted_words() {
+        assert_eq!(split_command("w \"My File.png\""), vec!["w", "My File.png"]);
+        assert_eq!(split_command("w a\"b c\"d"), vec!["w", "ab cd"]);
+        assert_eq!(split_command("w \"\""), vec!["w", ""]);
+        assert_eq!(split_command("w \"unterminated quote "), vec!["w", "unterminated quote "]);
+    }
+
+    #[test]
+    fn split_escaped_characters() {
+        assert_eq!(split_command("w My\\ File.png"), vec!["w", "My File.png"]);
+        assert_eq!(split_command("w \\\"quoted\\\""), vec!["w", "\"quoted\""]);
+        assert_eq!(split_command("w a\\\\b"), vec!["w", "a\\b"]);
+        assert_eq!(split_command("w a\\"), vec!["w", "a\\"]);
+    }
+
+    /// A UI whose `d` verb records the positions it is applied on, with a `gg` object moving the
+    /// cursor to the top-left corner and the cursor at (3, 4).
+    fn ui_with_gg() -> Ui<Vec<Vec<(usize, usize)>>> {
+        let mut ui = Ui::new(|_, _, _| {});
+        ui.add_verb("d", true, true, |_, applied:&mut Vec<Vec<(usize, usize)>>, positions| {
+            let mut positions : Vec<_> = positions.unwrap().iter().cloned().collect();
+            positions.sort();
+            applied.push(positions);
+        });
+        ui.add_object("gg", |ui, _, positions| {
+            positions.insert(ui.cursor());
+            ui.set_cursor(0, 0);
+            positions.insert(ui.cursor());
+        });
+        ui.set_cursor(3, 4);
+        ui
+    }
+
+    fn type_keys<T>(ui:&mut Ui<T>, env:&mut T, keys:&str) {
+        for key in KeySequence::try_from(keys).unwrap().seq {
+            ui.dispatch_key(env, key);
+        }
+    }
+
+    #[test]
+    fn object_of_several_keys() {
+        let mut ui = ui_with_gg();
+        let mut applied = Vec::new();
+        type_keys(&mut ui, &mut applied, "g");
+        assert_eq!(ui.cursor(), (3, 4));
+        type_keys(&mut ui, &mut applied, "g");
+        assert_eq!(ui.cursor(), (0, 0));
+        assert!(applied.is_empty());
+    }
+
+    #[test]
+    fn verb_on_object_of_several_keys() {
+        let mut ui = ui_with_gg();
+        let mut applied = Vec::new();
+        type_keys(&mut ui, &mut applied, "2dgg");
+        assert_eq!(applied, vec![vec![(0, 0), (3, 4)], vec![(0, 0)]]);
+        assert_eq!(ui.cursor(), (0, 0));
+    }
+
+    #[test]
+    fn escape_drops_keys_of_object() {
+        let mut ui = ui_with_gg();
+        let mut applied = Vec::new();
+        type_keys(&mut ui, &mut applied, "dg<Esc>g");
+        assert_eq!(ui.cursor(), (3, 4));
+        assert!(applied.is_empty());
+    }
 }